@@ -0,0 +1,365 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::{Mutex, Notify};
+
+use crate::memory::State;
+use crate::{Job, JobFilter, JobId, JobQueue, JobStatus, Page, QueueConfig, QueueError, Stats};
+
+// The async counterpart of JobQueue, for services running on tokio. Nothing
+// here blocks an executor thread.
+pub trait AsyncJobQueue: Send + Sync {
+    fn enqueue(&self, j: Job) -> impl Future<Output = Result<JobId, QueueError>> + Send;
+    fn get(&self, id_job: JobId) -> impl Future<Output = Result<Option<Job>, QueueError>> + Send;
+    fn dequeue(&self, id_job: JobId) -> impl Future<Output = Result<(), QueueError>> + Send;
+    fn dequeue_if(
+        &self,
+        id_job: JobId,
+        status: JobStatus,
+    ) -> impl Future<Output = Result<Job, QueueError>> + Send;
+    fn claim(&self) -> impl Future<Output = Result<Option<Job>, QueueError>> + Send;
+    // Resolves to the next job that could be claimed, waiting for one to be
+    // enqueued or become due for as long as it takes.
+    fn next_job(&self) -> impl Future<Output = Result<Job, QueueError>> + Send;
+    fn ack(&self, id_job: JobId) -> impl Future<Output = Result<(), QueueError>> + Send;
+    fn fail(
+        &self,
+        id_job: JobId,
+        reason: &str,
+    ) -> impl Future<Output = Result<(), QueueError>> + Send;
+    fn retry(&self, id_job: JobId) -> impl Future<Output = Result<(), QueueError>> + Send;
+    fn heartbeat(&self, id_job: JobId) -> impl Future<Output = Result<(), QueueError>> + Send;
+    fn reap(&self) -> impl Future<Output = Result<usize, QueueError>> + Send;
+    fn len(&self) -> impl Future<Output = Result<usize, QueueError>> + Send;
+    fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> impl Future<Output = Result<Page, QueueError>> + Send;
+    fn stats(&self) -> impl Future<Output = Result<Stats, QueueError>> + Send;
+
+    fn is_empty(&self) -> impl Future<Output = Result<bool, QueueError>> + Send {
+        async { Ok(self.len().await? == 0) }
+    }
+}
+
+// Same semantics as InMemQueue, but behind a tokio Mutex, with tasks
+// waiting in next_job woken up through `available`.
+#[derive(Default)]
+pub struct AsyncInMemQueue {
+    jobs: Mutex<State>,
+    available: Notify,
+    config: QueueConfig,
+}
+
+impl AsyncInMemQueue {
+    pub fn new() -> Self {
+        AsyncInMemQueue::default()
+    }
+
+    pub fn with_config(mut self, config: QueueConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    async fn update<F>(&self, id_job: JobId, f: F) -> Result<(), QueueError>
+    where
+        F: FnOnce(&mut Job, SystemTime) -> Result<(), QueueError>,
+    {
+        let now = self.config.clock.now();
+        self.jobs
+            .lock()
+            .await
+            .update(id_job, now, |job| f(job, now))
+    }
+}
+
+impl AsyncJobQueue for AsyncInMemQueue {
+    async fn enqueue(&self, j: Job) -> Result<JobId, QueueError> {
+        let id = self.jobs.lock().await.enqueue(j, &self.config)?;
+        self.available.notify_waiters();
+        Ok(id)
+    }
+
+    async fn get(&self, id_job: JobId) -> Result<Option<Job>, QueueError> {
+        Ok(self.jobs.lock().await.lookup(id_job).cloned())
+    }
+
+    async fn dequeue(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.jobs.lock().await.dequeue(id_job)
+    }
+
+    async fn dequeue_if(&self, id_job: JobId, status: JobStatus) -> Result<Job, QueueError> {
+        let mut state = self.jobs.lock().await;
+        let seq = state.find_in(id_job, status)?;
+        state.remove(seq).ok_or(QueueError::NotFound(id_job))
+    }
+
+    async fn claim(&self) -> Result<Option<Job>, QueueError> {
+        self.jobs.lock().await.claim(self.config.clock.now())
+    }
+
+    async fn next_job(&self) -> Result<Job, QueueError> {
+        loop {
+            // register interest before looking, so that a job enqueued right
+            // after the check still wakes us up
+            let notified = self.available.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let now = self.config.clock.now();
+            let next = {
+                let mut state = self.jobs.lock().await;
+                if let Some(job) = state.claim(now)? {
+                    return Ok(job);
+                }
+                state.next_scheduled()
+            };
+            match next {
+                Some(at) => {
+                    let wait = at.duration_since(now).unwrap_or_default();
+                    let _ = tokio::time::timeout(wait, notified).await;
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    async fn ack(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, _| job.ack()).await
+    }
+
+    async fn fail(&self, id_job: JobId, reason: &str) -> Result<(), QueueError> {
+        self.update(id_job, |job, now| job.fail(reason, &self.config.retry, now))
+            .await?;
+        self.available.notify_waiters();
+        Ok(())
+    }
+
+    async fn retry(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, _| job.revive()).await?;
+        self.available.notify_waiters();
+        Ok(())
+    }
+
+    async fn heartbeat(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, now| job.touch(now)).await
+    }
+
+    async fn reap(&self) -> Result<usize, QueueError> {
+        let reaped = self
+            .jobs
+            .lock()
+            .await
+            .reap(self.config.clock.now(), &self.config)?;
+        if reaped > 0 {
+            self.available.notify_waiters();
+        }
+        Ok(reaped)
+    }
+
+    async fn len(&self) -> Result<usize, QueueError> {
+        Ok(self.jobs.lock().await.len())
+    }
+
+    async fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<Page, QueueError> {
+        let state = self.jobs.lock().await;
+        Ok(state.query(filter, after, limit, self.config.clock.now()))
+    }
+
+    async fn stats(&self) -> Result<Stats, QueueError> {
+        Ok(self.jobs.lock().await.stats(self.config.clock.now()))
+    }
+}
+
+// Runs any blocking JobQueue on tokio's blocking thread pool.
+//
+// next_job keeps a blocking thread busy in claim_wait for up to
+// `poll_interval` at a time. If its future is dropped while a claim_wait is
+// in flight, the job that claim_wait picks is only handed back by the
+// reaper once its lease expires.
+pub struct Blocking<Q> {
+    queue: Arc<Q>,
+    poll_interval: Duration,
+}
+
+impl<Q> Blocking<Q>
+where
+    Q: JobQueue + Send + Sync + 'static,
+{
+    pub fn new(queue: Arc<Q>) -> Self {
+        Blocking {
+            queue,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn inner(&self) -> &Arc<Q> {
+        &self.queue
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, QueueError>
+    where
+        F: FnOnce(&Q) -> Result<T, QueueError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let queue = Arc::clone(&self.queue);
+        tokio::task::spawn_blocking(move || f(&queue))
+            .await
+            .map_err(|e| QueueError::Backend(Box::new(e)))?
+    }
+}
+
+impl<Q> AsyncJobQueue for Blocking<Q>
+where
+    Q: JobQueue + Send + Sync + 'static,
+{
+    async fn enqueue(&self, j: Job) -> Result<JobId, QueueError> {
+        self.run(move |q| q.enqueue(j)).await
+    }
+
+    async fn get(&self, id_job: JobId) -> Result<Option<Job>, QueueError> {
+        self.run(move |q| q.get(id_job)).await
+    }
+
+    async fn dequeue(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.run(move |q| q.dequeue(id_job)).await
+    }
+
+    async fn dequeue_if(&self, id_job: JobId, status: JobStatus) -> Result<Job, QueueError> {
+        self.run(move |q| q.dequeue_if(id_job, status)).await
+    }
+
+    async fn claim(&self) -> Result<Option<Job>, QueueError> {
+        self.run(|q| q.claim()).await
+    }
+
+    async fn next_job(&self) -> Result<Job, QueueError> {
+        loop {
+            let poll = self.poll_interval;
+            if let Some(job) = self.run(move |q| q.claim_wait(poll)).await? {
+                return Ok(job);
+            }
+        }
+    }
+
+    async fn ack(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.run(move |q| q.ack(id_job)).await
+    }
+
+    async fn fail(&self, id_job: JobId, reason: &str) -> Result<(), QueueError> {
+        let reason = reason.to_string();
+        self.run(move |q| q.fail(id_job, &reason)).await
+    }
+
+    async fn retry(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.run(move |q| q.retry(id_job)).await
+    }
+
+    async fn heartbeat(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.run(move |q| q.heartbeat(id_job)).await
+    }
+
+    async fn reap(&self) -> Result<usize, QueueError> {
+        self.run(|q| q.reap()).await
+    }
+
+    async fn len(&self) -> Result<usize, QueueError> {
+        self.run(|q| q.len()).await
+    }
+
+    async fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<Page, QueueError> {
+        let filter = filter.clone();
+        self.run(move |q| q.query(&filter, after, limit)).await
+    }
+
+    async fn stats(&self) -> Result<Stats, QueueError> {
+        self.run(|q| q.stats()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InMemQueue;
+
+    async fn round_trip<Q: AsyncJobQueue + 'static>(q: Arc<Q>) {
+        let waiter = {
+            let q = Arc::clone(&q);
+            tokio::spawn(async move { q.next_job().await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        q.enqueue(Job::new(1, b"async")).await.unwrap();
+
+        let job = waiter.await.unwrap().unwrap();
+        assert_eq!(job.get_id(), 1);
+        assert_eq!(job.get_status(), &JobStatus::PICKED);
+        q.heartbeat(1).await.unwrap();
+        q.ack(1).await.unwrap();
+
+        assert_eq!(q.enqueue(Job::anonymous(b"doomed")).await.unwrap(), 2);
+        assert_eq!(q.claim().await.unwrap().unwrap().get_id(), 2);
+        q.fail(2, "nope").await.unwrap();
+        let job = q.get(2).await.unwrap().unwrap();
+        assert_eq!(job.last_error(), Some("nope"));
+
+        let failed = JobFilter::new().with_status(JobStatus::FAILED);
+        let page = q.query(&failed, None, 10).await.unwrap();
+        assert_eq!(page.jobs.len(), 1);
+        assert_eq!(page.jobs[0].get_id(), 2);
+
+        let stats = q.stats().await.unwrap();
+        assert_eq!((stats.processed, stats.failed), (1, 1));
+
+        q.retry(2).await.unwrap();
+        let job = q.next_job().await.unwrap();
+        assert_eq!((job.get_id(), job.attempts()), (2, 1));
+        assert_eq!(job.errors(), ["nope"]);
+        q.ack(2).await.unwrap();
+
+        q.dequeue(1).await.unwrap();
+        assert_eq!(q.len().await.unwrap(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn in_mem_test() {
+        round_trip(Arc::new(AsyncInMemQueue::new())).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocking_adapter_test() {
+        let q = Blocking::new(Arc::new(InMemQueue::new()))
+            .with_poll_interval(Duration::from_millis(50));
+        round_trip(Arc::new(q)).await;
+    }
+
+    #[tokio::test]
+    async fn next_job_waits_for_schedule_test() {
+        let q = AsyncInMemQueue::new();
+        q.enqueue(Job::new(1, b"soon").with_delay(Duration::from_millis(30)))
+            .await
+            .unwrap();
+        let job = tokio::time::timeout(Duration::from_secs(5), q.next_job())
+            .await
+            .expect("next_job should wake up when the job is due")
+            .unwrap();
+        assert_eq!(job.get_id(), 1);
+    }
+}
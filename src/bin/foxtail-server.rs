@@ -0,0 +1,202 @@
+// Hosts a queue for RemoteQueue clients, see src/net.rs for the protocol.
+//
+//     foxtail-server [--listen ADDR] [--resp ADDR] [--http ADDR]
+//                    [--wal DIR | --sqlite PATH] [--lease SECS]
+//
+// Without --wal or --sqlite the queue lives in memory and is gone when the
+// server stops. --resp serves Redis clients too, see src/resp.rs: each key
+// is a queue of its own, next to the "default" one served to RemoteQueues.
+// --http serves the admin API of src/http.rs over the same queues, with the
+// `http` feature. Both need a backend with named queues, so they don't go
+// with --wal.
+use std::net::TcpListener;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use foxtail::{
+    InMemStore, JobQueue, QueueConfig, QueueError, QueueStore, Reaper, RespServer, Server,
+    WalQueue, DEFAULT_ADDR,
+};
+
+const USAGE: &str = "usage: foxtail-server [--listen ADDR] [--resp ADDR] [--http ADDR] \
+                     [--wal DIR | --sqlite PATH] [--lease SECS]";
+
+// The queue of a store served to RemoteQueues.
+const DEFAULT_QUEUE: &str = "default";
+
+enum Backend {
+    Memory,
+    Wal(String),
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
+}
+
+struct Options {
+    listen: String,
+    resp: Option<String>,
+    #[cfg(feature = "http")]
+    http: Option<String>,
+    backend: Backend,
+    lease: Option<Duration>,
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut opts = Options {
+        listen: DEFAULT_ADDR.to_string(),
+        resp: None,
+        #[cfg(feature = "http")]
+        http: None,
+        backend: Backend::Memory,
+        lease: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--listen" => opts.listen = value()?,
+            "--resp" => opts.resp = Some(value()?),
+            #[cfg(feature = "http")]
+            "--http" => opts.http = Some(value()?),
+            "--wal" => opts.backend = Backend::Wal(value()?),
+            #[cfg(feature = "sqlite")]
+            "--sqlite" => opts.backend = Backend::Sqlite(value()?),
+            "--lease" => {
+                let secs = value()?;
+                let secs = secs
+                    .parse()
+                    .map_err(|_| format!("invalid lease {:?}", secs))?;
+                opts.lease = Some(Duration::from_secs(secs));
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            other => return Err(format!("unknown argument {:?}\n{}", other, USAGE)),
+        }
+    }
+    Ok(opts)
+}
+
+// Serves `queue` and reaps expired leases every second until the listener
+// fails.
+fn run<Q>(queue: Q, opts: &Options) -> Result<(), String>
+where
+    Q: JobQueue + Send + Sync + 'static,
+{
+    let queue = Arc::new(queue);
+    let listener = bind(&opts.listen)?;
+    eprintln!("foxtail-server listening on {}", opts.listen);
+    let _reaper = Reaper::spawn(Arc::clone(&queue), Duration::from_secs(1));
+    Server::new(queue)
+        .serve(listener)
+        .map_err(|e| format!("server failed: {}", e))
+}
+
+// Serves `queue`, the default queue of `store`, and with --resp and --http
+// every queue of the store, --resp creating queues with `config`. The other
+// queues are reaped here, the default one by `run`.
+fn run_store<S>(
+    store: S,
+    queue: S::Queue,
+    config: QueueConfig,
+    opts: &Options,
+) -> Result<(), String>
+where
+    S: QueueStore + Send + Sync + 'static,
+    S::Queue: Send + Sync + 'static,
+{
+    let store = Arc::new(store);
+    #[cfg(feature = "http")]
+    if let Some(addr) = &opts.http {
+        let api = foxtail::HttpApi::new(Arc::clone(&store));
+        let server = tiny_http::Server::http(addr.as_str())
+            .map_err(|e| format!("can't listen on {}: {}", addr, e))?;
+        eprintln!("foxtail-server serving HTTP on {}", addr);
+        thread::spawn(move || api.serve(&server));
+    }
+    if let Some(addr) = &opts.resp {
+        let resp = RespServer::new(Arc::clone(&store)).with_config(config.clone());
+        let listener = bind(addr)?;
+        eprintln!("foxtail-server speaking RESP on {}", addr);
+        thread::spawn(move || {
+            if let Err(e) = resp.serve(listener) {
+                eprintln!("RESP server failed: {}", e);
+                process::exit(1);
+            }
+        });
+    }
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let names = store.queues().unwrap_or_default();
+        for name in names.iter().filter(|name| *name != DEFAULT_QUEUE) {
+            if let Err(e) = store.queue(name).and_then(|q| q.reap()) {
+                eprintln!("reaping {} failed: {}", name, e);
+            }
+        }
+    });
+    run(queue, opts)
+}
+
+// The default queue of `store`, created with `config` if needed.
+fn default_queue<S: QueueStore>(store: &S, config: QueueConfig) -> Result<S::Queue, QueueError> {
+    match store.create_queue(DEFAULT_QUEUE, config) {
+        Err(QueueError::QueueExists(_)) => store.queue(DEFAULT_QUEUE),
+        res => res,
+    }
+}
+
+fn bind(addr: &str) -> Result<TcpListener, String> {
+    TcpListener::bind(addr).map_err(|e| format!("can't listen on {}: {}", addr, e))
+}
+
+fn main() {
+    let opts = parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let config = |current: &QueueConfig| QueueConfig {
+        lease: opts.lease.unwrap_or(current.lease),
+        ..current.clone()
+    };
+    let result = match &opts.backend {
+        Backend::Memory => {
+            let store = InMemStore::new();
+            let config = config(&QueueConfig::default());
+            match default_queue(&store, config.clone()) {
+                Ok(q) => run_store(store, q, config, &opts),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Backend::Wal(_) if opts.resp.is_some() => {
+            Err("--resp needs the in-memory or SQLite backend".to_string())
+        }
+        #[cfg(feature = "http")]
+        Backend::Wal(_) if opts.http.is_some() => {
+            Err("--http needs the in-memory or SQLite backend".to_string())
+        }
+        Backend::Wal(dir) => match WalQueue::open(dir) {
+            Ok(q) => {
+                let config = config(q.config());
+                run(q.with_config(config), &opts)
+            }
+            Err(e) => Err(format!("can't open {}: {}", dir, e)),
+        },
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite(path) => match foxtail::SqliteStore::open(path) {
+            Ok(store) => {
+                let new = config(&QueueConfig::default());
+                // an existing queue keeps its stored config, but for --lease
+                match default_queue(&store, new.clone()) {
+                    Ok(q) => {
+                        let config = config(q.config());
+                        run_store(store, q.with_config(config), new, &opts)
+                    }
+                    Err(e) => Err(format!("can't open {}: {}", path, e)),
+                }
+            }
+            Err(e) => Err(format!("can't open {}: {}", path, e)),
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
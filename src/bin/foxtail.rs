@@ -0,0 +1,488 @@
+// Inspects and manipulates a queue from the command line, either straight
+// from its SQLite database or write-ahead log, or through foxtail-server.
+//
+//     foxtail --sqlite jobs.db stats
+//     foxtail --server 127.0.0.1:7878 list --status failed --json
+//     echo '{"to": "ada"}' | foxtail --wal ./queue enqueue --kind email
+//
+// A write-ahead log can't be opened while another process has it open,
+// talk to that process's server instead.
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use foxtail::{Job, JobFilter, JobId, JobQueue, JobStatus, RemoteQueue, WalQueue};
+
+#[derive(Parser)]
+#[command(name = "foxtail", about = "Inspect and manipulate foxtail queues")]
+struct Cli {
+    #[command(flatten)]
+    target: Target,
+    #[cfg(feature = "sqlite")]
+    #[arg(
+        long,
+        value_name = "NAME",
+        requires = "sqlite",
+        help = "Queue of the SQLite database to open [default: default]"
+    )]
+    queue: Option<String>,
+    #[arg(long, global = true, help = "Print JSON instead of text")]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+struct Target {
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "PATH", help = "Open a SQLite database")]
+    sqlite: Option<PathBuf>,
+    #[arg(long, value_name = "DIR", help = "Open a write-ahead log directory")]
+    wal: Option<PathBuf>,
+    #[arg(long, value_name = "ADDR", help = "Connect to a foxtail-server")]
+    server: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Add a job, its payload read from stdin unless --file is given")]
+    Enqueue {
+        #[arg(long, value_name = "PATH", help = "Read the payload from a file")]
+        file: Option<PathBuf>,
+        #[arg(long, default_value = "")]
+        kind: String,
+        #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+        priority: i32,
+        #[arg(long, value_name = "SECS", help = "Don't run the job before this")]
+        delay: Option<u64>,
+    },
+    #[command(about = "List jobs in id order")]
+    List {
+        #[arg(long)]
+        status: Option<Status>,
+        #[arg(long)]
+        kind: Option<String>,
+        #[arg(long, value_name = "ID", help = "Start after this job id")]
+        after: Option<JobId>,
+        #[arg(
+            long,
+            default_value_t = 100,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        limit: usize,
+    },
+    #[command(about = "Show one job")]
+    Show { id: JobId },
+    #[command(about = "Put a FAILED job back to PENDING")]
+    Retry { id: JobId },
+    #[command(about = "Remove every job, or only those with --status")]
+    Purge {
+        #[arg(long)]
+        status: Option<Status>,
+    },
+    #[command(about = "Count the jobs per status")]
+    Stats,
+    #[command(about = "Print jobs as they are enqueued, until interrupted")]
+    Tail {
+        #[arg(
+            long,
+            value_name = "MS",
+            default_value_t = 500,
+            help = "How often to poll"
+        )]
+        interval: u64,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Status {
+    Pending,
+    Picked,
+    Processed,
+    Failed,
+}
+
+impl From<Status> for JobStatus {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Pending => JobStatus::PENDING,
+            Status::Picked => JobStatus::PICKED,
+            Status::Processed => JobStatus::PROCESSED,
+            Status::Failed => JobStatus::FAILED,
+        }
+    }
+}
+
+type CliResult = Result<(), Box<dyn Error>>;
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = open_and_run(&cli) {
+        // `foxtail tail | head` ending isn't a failure
+        if e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+        {
+            return;
+        }
+        eprintln!("foxtail: {}", e);
+        process::exit(1);
+    }
+}
+
+fn open_and_run(cli: &Cli) -> CliResult {
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &cli.target.sqlite {
+        if !path.exists() {
+            return Err(format!("{} doesn't exist", path.display()).into());
+        }
+        let store = foxtail::SqliteStore::open(path)?;
+        let name = cli.queue.as_deref().unwrap_or("default");
+        return run(
+            &foxtail::QueueStore::queue(&store, name)?,
+            cli,
+            &mut io::stdout().lock(),
+        );
+    }
+    if let Some(dir) = &cli.target.wal {
+        if !dir.is_dir() {
+            return Err(format!("{} isn't a directory", dir.display()).into());
+        }
+        return run(&WalQueue::open(dir)?, cli, &mut io::stdout().lock());
+    }
+    if let Some(addr) = &cli.target.server {
+        return run(&RemoteQueue::connect(addr)?, cli, &mut io::stdout().lock());
+    }
+    unreachable!("clap requires a target")
+}
+
+// How many jobs `purge` and `tail` fetch at a time.
+const PAGE: usize = 100;
+
+fn run<Q: JobQueue>(q: &Q, cli: &Cli, out: &mut impl Write) -> CliResult {
+    match &cli.command {
+        Command::Enqueue {
+            file,
+            kind,
+            priority,
+            delay,
+        } => {
+            let payload = match file {
+                Some(path) => fs::read(path)?,
+                None => {
+                    let mut buf = Vec::new();
+                    io::stdin().read_to_end(&mut buf)?;
+                    buf
+                }
+            };
+            let mut job = Job::anonymous(&payload)
+                .with_kind(kind)
+                .with_priority(*priority);
+            if let Some(secs) = delay {
+                job = job.with_delay(Duration::from_secs(*secs));
+            }
+            let id = q.enqueue(job)?;
+            if cli.json {
+                writeln!(out, "{}", serde_json::json!({ "id": id.to_string() }))?;
+            } else {
+                writeln!(out, "{}", id)?;
+            }
+        }
+        Command::List {
+            status,
+            kind,
+            after,
+            limit,
+        } => {
+            let mut filter = JobFilter::new();
+            if let Some(status) = status {
+                filter = filter.with_status((*status).into());
+            }
+            if let Some(kind) = kind {
+                filter = filter.with_kind(kind);
+            }
+            let page = q.query(&filter, *after, *limit)?;
+            if cli.json {
+                let jobs: Vec<_> = page.jobs.iter().map(Job::to_json).collect();
+                let next = page.next.map(|id| id.to_string());
+                let page = serde_json::json!({ "jobs": jobs, "next": next });
+                writeln!(out, "{}", page)?;
+            } else {
+                writeln!(out, "{}", header())?;
+                for job in &page.jobs {
+                    writeln!(out, "{}", line(job))?;
+                }
+                if let Some(next) = page.next {
+                    writeln!(out, "... more with --after {}", next)?;
+                }
+            }
+        }
+        Command::Show { id } => {
+            let job = q.get(*id)?.ok_or(foxtail::QueueError::NotFound(*id))?;
+            if cli.json {
+                writeln!(out, "{}", job.to_json())?;
+            } else {
+                describe(out, &job)?;
+            }
+        }
+        Command::Retry { id } => {
+            q.retry(*id)?;
+            let job = q.get(*id)?.ok_or(foxtail::QueueError::NotFound(*id))?;
+            if cli.json {
+                writeln!(out, "{}", job.to_json())?;
+            } else {
+                writeln!(out, "job {} is PENDING again", id)?;
+            }
+        }
+        Command::Purge { status } => {
+            let status = status.map(JobStatus::from);
+            let mut filter = JobFilter::new();
+            if let Some(status) = status {
+                filter = filter.with_status(status);
+            }
+            let mut purged = 0;
+            let mut after = None;
+            loop {
+                let page = q.query(&filter, after, PAGE)?;
+                for job in &page.jobs {
+                    // only if it's still in that status, a FAILED job may
+                    // have been retried and picked up since the query
+                    let res = match status {
+                        Some(status) => q.dequeue_if(job.get_id(), status).map(drop),
+                        None => q.dequeue(job.get_id()),
+                    };
+                    match res {
+                        Ok(()) => purged += 1,
+                        // gone already, or moved on
+                        Err(foxtail::QueueError::NotFound(_))
+                        | Err(foxtail::QueueError::WrongStatus { .. }) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                after = page.next;
+                if after.is_none() {
+                    break;
+                }
+            }
+            if cli.json {
+                writeln!(out, "{}", serde_json::json!({ "purged": purged }))?;
+            } else {
+                writeln!(out, "purged {} jobs", purged)?;
+            }
+        }
+        Command::Stats => {
+            let stats = q.stats()?;
+            if cli.json {
+                writeln!(out, "{}", stats.to_json())?;
+            } else {
+                for status in [
+                    JobStatus::PENDING,
+                    JobStatus::PICKED,
+                    JobStatus::PROCESSED,
+                    JobStatus::FAILED,
+                ] {
+                    writeln!(
+                        out,
+                        "{:<16}{}",
+                        format!("{:?}", status),
+                        stats.count(status)
+                    )?;
+                }
+                writeln!(out, "{:<16}{}", "total", stats.total())?;
+                let timings = [
+                    ("oldest pending", stats.oldest_pending),
+                    ("avg pick", stats.avg_pick),
+                    ("avg completion", stats.avg_completion),
+                ];
+                for (name, d) in timings {
+                    let d = d.map_or("-".to_string(), |d| format!("{:.3?}", d));
+                    writeln!(out, "{:<16}{}", name, d)?;
+                }
+            }
+        }
+        Command::Tail { interval } => {
+            // only jobs with an id above the newest one right now
+            let mut after = newest(q)?;
+            if !cli.json {
+                writeln!(out, "{}", header())?;
+            }
+            loop {
+                let page = q.query(&JobFilter::new(), after, PAGE)?;
+                for job in &page.jobs {
+                    if cli.json {
+                        writeln!(out, "{}", job.to_json())?;
+                    } else {
+                        writeln!(out, "{}", line(job))?;
+                    }
+                    after = Some(job.get_id());
+                }
+                out.flush()?;
+                if page.next.is_none() {
+                    thread::sleep(Duration::from_millis(*interval));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The id of the newest job, paging through the queue rather than fetching
+// it all at once.
+fn newest<Q: JobQueue>(q: &Q) -> Result<Option<JobId>, foxtail::QueueError> {
+    let mut after = None;
+    loop {
+        let page = q.query(&JobFilter::new(), after, PAGE)?;
+        if page.next.is_none() {
+            return Ok(page.jobs.last().map(Job::get_id).or(after));
+        }
+        after = page.next;
+    }
+}
+
+fn header() -> String {
+    format!(
+        "{:>8}  {:<9}  {:<12}  {:>8}  {}",
+        "ID", "STATUS", "KIND", "ATTEMPTS", "CREATED"
+    )
+}
+
+fn line(job: &Job) -> String {
+    format!(
+        "{:>8}  {:<9}  {:<12}  {:>8}  {}",
+        job.get_id(),
+        format!("{:?}", job.get_status()),
+        if job.kind().is_empty() {
+            "-"
+        } else {
+            job.kind()
+        },
+        job.attempts(),
+        relative(job.timestamp())
+    )
+}
+
+fn describe(out: &mut impl Write, job: &Job) -> io::Result<()> {
+    writeln!(out, "id:         {}", job.get_id())?;
+    writeln!(out, "status:     {:?}", job.get_status())?;
+    if !job.kind().is_empty() {
+        writeln!(out, "kind:       {}", job.kind())?;
+    }
+    writeln!(out, "priority:   {}", job.priority())?;
+    writeln!(out, "attempts:   {}", job.attempts())?;
+    writeln!(out, "expiries:   {}", job.expiries())?;
+    writeln!(out, "created:    {}", relative(job.timestamp()))?;
+    writeln!(out, "heartbeat:  {}", relative(job.heartbeat()))?;
+    if let Some(at) = job.run_at() {
+        writeln!(out, "run at:     {}", relative(at))?;
+    }
+    if let Some(policy) = job.retry_policy() {
+        writeln!(out, "retry:      {}", policy)?;
+    }
+    if let Some(codec) = job.codec() {
+        writeln!(out, "codec:      {}", codec)?;
+    }
+    for (i, error) in job.errors().iter().enumerate() {
+        let label = if i == 0 { "errors:" } else { "" };
+        writeln!(out, "{:<12}{}", label, error)?;
+    }
+    match std::str::from_utf8(job.payload()) {
+        Ok(text) => writeln!(out, "payload:    {}", text),
+        Err(_) => writeln!(out, "payload:    <{} bytes>", job.payload().len()),
+    }
+}
+
+// "3m ago" or "in 10s", to the largest whole unit.
+fn relative(t: &SystemTime) -> String {
+    let (d, past) = match SystemTime::now().duration_since(*t) {
+        Ok(d) => (d, true),
+        Err(e) => (e.duration(), false),
+    };
+    let secs = d.as_secs();
+    let amount = match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    };
+    if past {
+        format!("{} ago", amount)
+    } else {
+        format!("in {}", amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use foxtail::InMemQueue;
+
+    // Runs `foxtail --server - args...` against `q`, returning its output.
+    fn foxtail(q: &InMemQueue, args: &[&str]) -> String {
+        let cli = Cli::try_parse_from(["foxtail", "--server", "-"].iter().chain(args)).unwrap();
+        let mut out = Vec::new();
+        run(q, &cli, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn relative_test() {
+        let now = SystemTime::now();
+        assert_eq!(relative(&now), "0s ago");
+        assert_eq!(relative(&(now - Duration::from_secs(90))), "1m ago");
+        assert_eq!(relative(&(now - Duration::from_secs(7200))), "2h ago");
+        assert_eq!(relative(&(now - Duration::from_secs(3 * 86400))), "3d ago");
+        assert_eq!(relative(&(now + Duration::from_millis(10_500))), "in 10s");
+    }
+
+    #[test]
+    fn status_test() {
+        assert_eq!(JobStatus::from(Status::Pending), JobStatus::PENDING);
+        assert_eq!(JobStatus::from(Status::Picked), JobStatus::PICKED);
+        assert_eq!(JobStatus::from(Status::Processed), JobStatus::PROCESSED);
+        assert_eq!(JobStatus::from(Status::Failed), JobStatus::FAILED);
+    }
+
+    #[test]
+    fn list_test() {
+        let q = InMemQueue::new();
+        for _ in 0..3 {
+            q.enqueue(Job::anonymous(b"x")).unwrap();
+        }
+        let out = foxtail(&q, &["list", "--limit", "2"]);
+        assert_eq!(out.lines().count(), 4);
+        assert!(out.ends_with("... more with --after 2\n"), "{}", out);
+        let args = ["foxtail", "--server", "-", "list", "--limit", "0"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn retry_purge_test() {
+        let q = InMemQueue::new();
+        for _ in 0..(PAGE + 5) {
+            q.enqueue(Job::anonymous(b"x")).unwrap();
+        }
+        for _ in 0..3 {
+            let id = q.claim().unwrap().unwrap().get_id();
+            q.fail(id, "boom").unwrap();
+        }
+
+        assert_eq!(foxtail(&q, &["retry", "2"]), "job 2 is PENDING again\n");
+        assert_eq!(q.get(2).unwrap().unwrap().get_status(), &JobStatus::PENDING);
+        assert_eq!(
+            foxtail(&q, &["purge", "--status", "failed", "--json"]),
+            "{\"purged\":2}\n"
+        );
+        assert_eq!(q.len().unwrap(), PAGE + 3);
+        // more than fits in a page
+        assert_eq!(
+            foxtail(&q, &["purge"]),
+            format!("purged {} jobs\n", PAGE + 3)
+        );
+        assert!(q.is_empty().unwrap());
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// Where queues get the time from, for job timestamps, heartbeats, leases,
+// schedules and backoff. Waiting (claim_wait, the reaper, workers) still
+// happens in real time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// A clock that only moves when told to, for tests. Clones share the same
+// time, so a test can keep one and hand the other to a queue.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl MockClock {
+    pub fn new(start: SystemTime) -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += d;
+    }
+
+    pub fn set(&self, t: SystemTime) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = t;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new(SystemTime::now())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_test() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let shared = clock.clone();
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH);
+        shared.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH + Duration::from_secs(5));
+        shared.set(SystemTime::UNIX_EPOCH);
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH);
+    }
+}
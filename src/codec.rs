@@ -0,0 +1,200 @@
+use std::error::Error;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Job, JobId};
+
+// Turns typed payloads into the bytes a Job carries and back. The TAG is
+// stored on the job next to the payload, so that consumers know how to
+// decode it regardless of what the producer picked.
+pub trait Codec {
+    const TAG: &'static str;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+pub struct Json;
+
+pub struct Bincode;
+
+pub struct MsgPack;
+
+#[derive(Debug)]
+pub enum CodecError {
+    // The job carries raw bytes (None) or was encoded with a codec that
+    // payload_as doesn't know about; use payload_with for custom codecs.
+    UnknownCodec(Option<String>),
+    Serde(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::UnknownCodec(None) => write!(f, "payload has no codec"),
+            CodecError::UnknownCodec(Some(tag)) => write!(f, "unknown codec {:?}", tag),
+            CodecError::Serde(e) => write!(f, "codec error: {}", e),
+        }
+    }
+}
+
+impl Error for CodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CodecError::Serde(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+fn serde_error<E: Error + Send + Sync + 'static>(e: E) -> CodecError {
+    CodecError::Serde(Box::new(e))
+}
+
+impl Codec for Json {
+    const TAG: &'static str = "json";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(serde_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(serde_error)
+    }
+}
+
+impl Codec for Bincode {
+    const TAG: &'static str = "bincode";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serde::encode_to_vec(value, bincode::config::standard()).map_err(serde_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map(|(value, _)| value)
+            .map_err(serde_error)
+    }
+}
+
+impl Codec for MsgPack {
+    const TAG: &'static str = "msgpack";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(value).map_err(serde_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(serde_error)
+    }
+}
+
+impl Job {
+    // A job whose payload is `value` encoded as JSON.
+    pub fn with_payload<T: Serialize>(id: JobId, value: &T) -> Result<Self, CodecError> {
+        Job::with_payload_codec::<Json, T>(id, value)
+    }
+
+    pub fn with_payload_codec<C: Codec, T: Serialize>(
+        id: JobId,
+        value: &T,
+    ) -> Result<Self, CodecError> {
+        let mut job = Job::new(id, &C::encode(value)?);
+        job.codec = Some(C::TAG.to_string());
+        Ok(job)
+    }
+
+    // Decodes the payload with whichever built-in codec encoded it.
+    pub fn payload_as<T: DeserializeOwned>(&self) -> Result<T, CodecError> {
+        match self.codec.as_deref() {
+            Some(Json::TAG) => Json::decode(&self.payload),
+            Some(Bincode::TAG) => Bincode::decode(&self.payload),
+            Some(MsgPack::TAG) => MsgPack::decode(&self.payload),
+            other => Err(CodecError::UnknownCodec(other.map(str::to_string))),
+        }
+    }
+
+    // Decodes the payload with C, which must be the codec it was encoded with.
+    pub fn payload_with<C: Codec, T: DeserializeOwned>(&self) -> Result<T, CodecError> {
+        match self.codec.as_deref() {
+            Some(tag) if tag == C::TAG => C::decode(&self.payload),
+            other => Err(CodecError::UnknownCodec(other.map(str::to_string))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemQueue, JobQueue};
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Email {
+        to: String,
+        subject: String,
+        retries: u8,
+    }
+
+    fn email() -> Email {
+        Email {
+            to: "bob@example.com".to_string(),
+            subject: "hi".to_string(),
+            retries: 3,
+        }
+    }
+
+    #[test]
+    fn codecs_test() {
+        let q = InMemQueue::new();
+        q.enqueue(Job::with_payload(1, &email()).unwrap()).unwrap();
+        q.enqueue(Job::with_payload_codec::<Bincode, _>(2, &email()).unwrap())
+            .unwrap();
+        q.enqueue(Job::with_payload_codec::<MsgPack, _>(3, &email()).unwrap())
+            .unwrap();
+
+        // consumers don't need to know which codec the producer used
+        for (id, tag) in [(1, "json"), (2, "bincode"), (3, "msgpack")] {
+            let job = q.get(id).unwrap().unwrap();
+            assert_eq!(job.codec(), Some(tag));
+            assert_eq!(job.payload_as::<Email>().unwrap(), email());
+        }
+
+        let job = q.get(2).unwrap().unwrap();
+        assert_eq!(job.payload_with::<Bincode, Email>().unwrap(), email());
+        assert!(matches!(
+            job.payload_with::<Json, Email>(),
+            Err(CodecError::UnknownCodec(Some(_)))
+        ));
+    }
+
+    #[test]
+    fn raw_and_custom_test() {
+        struct Upper;
+        impl Codec for Upper {
+            const TAG: &'static str = "upper";
+            fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+                Ok(Json::encode(value)?.to_ascii_uppercase())
+            }
+            fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+                Json::decode(&bytes.to_ascii_lowercase())
+            }
+        }
+
+        let raw = Job::new(1, b"not encoded");
+        assert!(matches!(
+            raw.payload_as::<String>(),
+            Err(CodecError::UnknownCodec(None))
+        ));
+
+        let job = Job::with_payload_codec::<Upper, _>(2, &"shout").unwrap();
+        assert_eq!(job.payload(), b"\"SHOUT\"");
+        assert!(job.payload_as::<String>().is_err());
+        assert_eq!(job.payload_with::<Upper, String>().unwrap(), "shout");
+
+        let json = Job::with_payload(3, &email()).unwrap();
+        assert!(json.payload_as::<u64>().is_err());
+    }
+}
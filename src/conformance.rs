@@ -0,0 +1,441 @@
+// Checks every JobQueue implementation must pass, so that backends can be
+// swapped without anything else noticing. Each check takes a function
+// building an empty queue with the given config and panics on the first
+// deviation. Time-dependent checks hand the queue a MockClock, so a
+// backend has to take the time from `QueueConfig::clock`.
+//
+// To run them all against a backend, in a test module:
+//
+//     foxtail::conformance_tests!(my_queue, |config| MyQueue::new().with_config(config));
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::{Job, JobId, JobQueue, JobStatus, MockClock, QueueConfig, QueueError, MAX_JOB_ID};
+
+// Generates a module named `$name` with one #[test] per check.
+#[macro_export]
+macro_rules! conformance_tests {
+    ($name:ident, $make:expr) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #[test]
+            fn fifo_order() {
+                $crate::conformance::fifo_order($make);
+            }
+
+            #[test]
+            fn priority_order() {
+                $crate::conformance::priority_order($make);
+            }
+
+            #[test]
+            fn ids() {
+                $crate::conformance::ids($make);
+            }
+
+            #[test]
+            fn not_found() {
+                $crate::conformance::not_found($make);
+            }
+
+            #[test]
+            fn transitions() {
+                $crate::conformance::transitions($make);
+            }
+
+            #[test]
+            fn retries() {
+                $crate::conformance::retries($make);
+            }
+
+            #[test]
+            fn timestamps() {
+                $crate::conformance::timestamps($make);
+            }
+
+            #[test]
+            fn lease_expiry() {
+                $crate::conformance::lease_expiry($make);
+            }
+
+            #[test]
+            fn concurrent_claims() {
+                $crate::conformance::concurrent_claims($make);
+            }
+        }
+    };
+}
+
+fn claim_all<Q: JobQueue>(q: &Q) -> Vec<JobId> {
+    std::iter::from_fn(|| q.claim().unwrap())
+        .map(|job| job.get_id())
+        .collect()
+}
+
+// Jobs of equal priority are claimed in the order they were enqueued.
+pub fn fifo_order<Q: JobQueue>(make: impl Fn(QueueConfig) -> Q) {
+    let q = make(QueueConfig::default());
+    assert!(q.is_empty().unwrap());
+    let ids: Vec<JobId> = (0..5)
+        .map(|_| q.enqueue(Job::anonymous(b"job")).unwrap())
+        .collect();
+    assert_eq!(q.len().unwrap(), 5);
+    let listed: Vec<JobId> = q.list().unwrap().iter().map(Job::get_id).collect();
+    assert_eq!(listed, ids);
+    assert_eq!(claim_all(&q), ids);
+}
+
+// Higher priorities first, jobs that aren't due yet not at all.
+pub fn priority_order<Q: JobQueue>(make: impl Fn(QueueConfig) -> Q) {
+    let q = make(QueueConfig::default());
+    let low = q.enqueue(Job::anonymous(b"low").with_priority(-1)).unwrap();
+    let normal = q.enqueue(Job::anonymous(b"normal")).unwrap();
+    let urgent = q
+        .enqueue(Job::anonymous(b"urgent").with_priority(5))
+        .unwrap();
+    q.enqueue(
+        Job::anonymous(b"later")
+            .with_priority(10)
+            .with_delay(Duration::from_secs(60)),
+    )
+    .unwrap();
+    assert_eq!(claim_all(&q), [urgent, normal, low]);
+}
+
+// Assigned ids are unique, caller-supplied ones are kept and can't be
+// enqueued twice.
+pub fn ids<Q: JobQueue>(make: impl Fn(QueueConfig) -> Q) {
+    let q = make(QueueConfig::default());
+    let mut seen = BTreeSet::new();
+    for _ in 0..10 {
+        let id = q.enqueue(Job::anonymous(b"x")).unwrap();
+        assert_ne!(id, 0);
+        assert!(seen.insert(id), "id {} handed out twice", id);
+    }
+    let mine = seen.last().unwrap() + 1000;
+    assert_eq!(q.enqueue(Job::new(mine, b"mine")).unwrap(), mine);
+    assert!(matches!(
+        q.enqueue(Job::new(mine, b"again")),
+        Err(QueueError::DuplicateId(id)) if id == mine
+    ));
+    assert_eq!(q.get(mine).unwrap().unwrap().payload(), b"mine");
+    assert!(q.enqueue(Job::anonymous(b"x")).unwrap() > mine);
+
+    // ids SQLite can't store are refused, and running out of them is an
+    // error rather than a panic that would poison the queue
+    assert!(matches!(
+        q.enqueue(Job::new(MAX_JOB_ID + 1, b"too big")),
+        Err(QueueError::IdOutOfRange(_))
+    ));
+    assert!(q.get(u64::MAX).unwrap().is_none());
+    assert_eq!(
+        q.enqueue(Job::new(MAX_JOB_ID, b"last")).unwrap(),
+        MAX_JOB_ID
+    );
+    assert!(matches!(
+        q.enqueue(Job::anonymous(b"one too many")),
+        Err(QueueError::IdOutOfRange(_))
+    ));
+    assert_eq!(q.len().unwrap(), 13);
+}
+
+pub fn not_found<Q: JobQueue>(make: impl Fn(QueueConfig) -> Q) {
+    let q = make(QueueConfig::default());
+    let id = q.enqueue(Job::anonymous(b"x")).unwrap();
+    q.dequeue(id).unwrap();
+    assert!(q.get(id).unwrap().is_none());
+    assert!(matches!(q.dequeue(id), Err(QueueError::NotFound(i)) if i == id));
+    assert!(matches!(q.ack(id), Err(QueueError::NotFound(i)) if i == id));
+    assert!(matches!(q.fail(id, "x"), Err(QueueError::NotFound(i)) if i == id));
+    assert!(matches!(q.heartbeat(id), Err(QueueError::NotFound(i)) if i == id));
+    assert!(q.claim().unwrap().is_none());
+}
+
+// Only the legal status transitions go through.
+pub fn transitions<Q: JobQueue>(make: impl Fn(QueueConfig) -> Q) {
+    let q = make(QueueConfig::default());
+    let ok = q.enqueue(Job::anonymous(b"ok")).unwrap();
+    let bad = q.enqueue(Job::anonymous(b"bad")).unwrap();
+    let job = q.get(ok).unwrap().unwrap();
+    assert_eq!(job.get_status(), &JobStatus::PENDING);
+    assert_eq!(job.attempts(), 0);
+
+    let invalid = |res: Result<(), QueueError>| {
+        assert!(
+            matches!(res, Err(QueueError::InvalidTransition { .. })),
+            "expected an invalid transition, got {:?}",
+            res
+        )
+    };
+    invalid(q.ack(ok));
+    invalid(q.fail(ok, "early"));
+    invalid(q.heartbeat(ok));
+
+    let job = q.claim().unwrap().unwrap();
+    assert_eq!(job.get_id(), ok);
+    assert_eq!(job.get_status(), &JobStatus::PICKED);
+    assert_eq!(job.attempts(), 1);
+    assert_eq!(q.get(ok).unwrap().unwrap().get_status(), &JobStatus::PICKED);
+    q.heartbeat(ok).unwrap();
+    q.ack(ok).unwrap();
+    assert_eq!(
+        q.get(ok).unwrap().unwrap().get_status(),
+        &JobStatus::PROCESSED
+    );
+    invalid(q.ack(ok));
+    invalid(q.fail(ok, "late"));
+
+    q.claim().unwrap();
+    q.fail(bad, "broken").unwrap();
+    let job = q.get(bad).unwrap().unwrap();
+    assert_eq!(job.get_status(), &JobStatus::FAILED);
+    assert_eq!(job.errors(), ["broken"]);
+    invalid(q.ack(bad));
+    assert!(q.claim().unwrap().is_none());
+
+    q.retry(bad).unwrap();
+    let job = q.get(bad).unwrap().unwrap();
+    assert_eq!(job.get_status(), &JobStatus::PENDING);
+    assert_eq!(job.attempts(), 0);
+    assert_eq!(job.errors(), ["broken"]);
+    assert!(matches!(
+        q.retry(bad),
+        Err(QueueError::WrongStatus {
+            status: JobStatus::PENDING,
+            expected: JobStatus::FAILED,
+            ..
+        })
+    ));
+    assert!(matches!(q.retry(bad + 1000), Err(QueueError::NotFound(_))));
+    assert_eq!(q.claim().unwrap().unwrap().get_id(), bad);
+    // a worker has it, retrying would hand it out twice
+    assert!(matches!(
+        q.retry(bad),
+        Err(QueueError::WrongStatus {
+            status: JobStatus::PICKED,
+            expected: JobStatus::FAILED,
+            ..
+        })
+    ));
+    assert!(q.claim().unwrap().is_none());
+
+    // dequeue_if only removes a job in the status asked for
+    let res = q.dequeue_if(bad, JobStatus::PENDING);
+    assert!(
+        matches!(
+            res,
+            Err(QueueError::WrongStatus {
+                status: JobStatus::PICKED,
+                expected: JobStatus::PENDING,
+                ..
+            })
+        ),
+        "expected a wrong status, got {:?}",
+        res
+    );
+    assert!(q.get(bad).unwrap().is_some());
+    let job = q.dequeue_if(bad, JobStatus::PICKED).unwrap();
+    assert_eq!(job.get_id(), bad);
+    assert_eq!(job.payload(), b"bad");
+    assert!(q.get(bad).unwrap().is_none());
+    assert!(matches!(
+        q.dequeue_if(bad, JobStatus::PICKED),
+        Err(QueueError::NotFound(_))
+    ));
+
+    // FAILED through its lease, with attempts to spare: only retry brings
+    // it back
+    let clock = MockClock::default();
+    let q = make(QueueConfig {
+        max_expiries: 0,
+        retry: crate::RetryPolicy::new(5, crate::Backoff::Fixed(Duration::ZERO)),
+        clock: Arc::new(clock.clone()),
+        ..QueueConfig::default()
+    });
+    let lost = q.enqueue(Job::anonymous(b"lost")).unwrap();
+    q.claim().unwrap();
+    clock.advance(QueueConfig::default().lease * 2);
+    assert_eq!(q.reap().unwrap(), 1);
+    assert_eq!(
+        q.get(lost).unwrap().unwrap().get_status(),
+        &JobStatus::FAILED
+    );
+    invalid(q.fail(lost, "late"));
+    assert_eq!(
+        q.get(lost).unwrap().unwrap().get_status(),
+        &JobStatus::FAILED
+    );
+    assert!(q.claim().unwrap().is_none());
+}
+
+// Failed jobs come back after their backoff while the policy allows it.
+pub fn retries<Q: JobQueue>(make: impl Fn(QueueConfig) -> Q) {
+    let clock = MockClock::default();
+    let q = make(QueueConfig {
+        retry: crate::RetryPolicy::new(2, crate::Backoff::Fixed(Duration::from_secs(10))),
+        clock: Arc::new(clock.clone()),
+        ..QueueConfig::default()
+    });
+    let id = q.enqueue(Job::anonymous(b"flaky")).unwrap();
+    q.claim().unwrap();
+    q.fail(id, "first").unwrap();
+    let job = q.get(id).unwrap().unwrap();
+    assert_eq!(job.get_status(), &JobStatus::PENDING);
+    clock.advance(Duration::from_secs(9));
+    assert!(q.claim().unwrap().is_none(), "claimed during backoff");
+
+    clock.advance(Duration::from_secs(1));
+    let job = q.claim().unwrap().unwrap();
+    assert_eq!(job.get_id(), id);
+    assert_eq!(job.attempts(), 2);
+    q.fail(id, "second").unwrap();
+    let job = q.get(id).unwrap().unwrap();
+    assert_eq!(job.get_status(), &JobStatus::FAILED);
+    assert_eq!(job.errors(), ["first", "second"]);
+}
+
+// Jobs made with Job::new are dated by the queue's clock when enqueued,
+// their delay included, while Job::new_at keeps the time it was given.
+pub fn timestamps<Q: JobQueue>(make: impl Fn(QueueConfig) -> Q) {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let clock = MockClock::new(start);
+    let q = make(QueueConfig {
+        clock: Arc::new(clock.clone()),
+        ..QueueConfig::default()
+    });
+    let later = Job::anonymous(b"later").with_delay(Duration::from_secs(60));
+    let later = q.enqueue(later).unwrap();
+    let now = q.enqueue(Job::anonymous(b"now")).unwrap();
+    let job = q.get(now).unwrap().unwrap();
+    assert_eq!(job.timestamp(), &start);
+    assert_eq!(job.heartbeat(), &start);
+    let job = q.get(later).unwrap().unwrap();
+    assert_eq!(job.run_at(), Some(&(start + Duration::from_secs(60))));
+    assert_eq!(claim_all(&q), [now]);
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(claim_all(&q), [later]);
+
+    let dated = start - Duration::from_secs(5);
+    let dated = q.enqueue(Job::new_at(0, b"dated", dated)).unwrap();
+    let job = q.get(dated).unwrap().unwrap();
+    assert_eq!(job.timestamp(), &(start - Duration::from_secs(5)));
+    // and so does the age of what's pending
+    let stats = q.stats().unwrap();
+    assert_eq!(stats.oldest_pending, Some(Duration::from_secs(65)));
+}
+
+// Jobs whose lease runs out go back to PENDING, then to FAILED once they
+// expired too often; heartbeats keep the lease alive.
+pub fn lease_expiry<Q: JobQueue>(make: impl Fn(QueueConfig) -> Q) {
+    let clock = MockClock::default();
+    let q = make(QueueConfig {
+        lease: Duration::from_secs(30),
+        max_expiries: 1,
+        clock: Arc::new(clock.clone()),
+        ..QueueConfig::default()
+    });
+    let slow = q.enqueue(Job::anonymous(b"slow")).unwrap();
+    let alive = q.enqueue(Job::anonymous(b"alive")).unwrap();
+    q.claim().unwrap();
+    q.claim().unwrap();
+
+    clock.advance(Duration::from_secs(20));
+    q.heartbeat(alive).unwrap();
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(q.reap().unwrap(), 1);
+    let job = q.get(slow).unwrap().unwrap();
+    assert_eq!(job.get_status(), &JobStatus::PENDING);
+    assert_eq!(job.expiries(), 1);
+    assert_eq!(
+        q.get(alive).unwrap().unwrap().get_status(),
+        &JobStatus::PICKED
+    );
+    q.ack(alive).unwrap();
+
+    assert_eq!(q.claim().unwrap().unwrap().get_id(), slow);
+    clock.advance(Duration::from_secs(31));
+    assert_eq!(q.reap().unwrap(), 1);
+    let job = q.get(slow).unwrap().unwrap();
+    assert_eq!(job.get_status(), &JobStatus::FAILED);
+    assert_eq!(job.expiries(), 2);
+    assert_eq!(q.reap().unwrap(), 0);
+}
+
+// Every job is claimed exactly once, however many threads compete for them.
+pub fn concurrent_claims<Q>(make: impl Fn(QueueConfig) -> Q)
+where
+    Q: JobQueue + Send + Sync + 'static,
+{
+    let q = Arc::new(make(QueueConfig::default()));
+    let ids: BTreeSet<JobId> = (0..200)
+        .map(|_| q.enqueue(Job::anonymous(b"work")).unwrap())
+        .collect();
+
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let q = Arc::clone(&q);
+            thread::spawn(move || {
+                let mut claimed = Vec::new();
+                while let Some(job) = q.claim().unwrap() {
+                    q.ack(job.get_id()).unwrap();
+                    claimed.push(job.get_id());
+                }
+                claimed
+            })
+        })
+        .collect();
+    let mut claimed = BTreeSet::new();
+    for worker in workers {
+        for id in worker.join().unwrap() {
+            assert!(claimed.insert(id), "job {} claimed twice", id);
+        }
+    }
+    assert_eq!(claimed, ids);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DeadLetter, InMemQueue, JobQueue, WalQueue};
+
+    conformance_tests!(in_mem, |config| InMemQueue::new().with_config(config));
+
+    conformance_tests!(wal, |config| WalQueue::new().with_config(config));
+
+    #[cfg(feature = "sqlite")]
+    conformance_tests!(sqlite, |config| crate::SqliteQueue::new()
+        .with_config(config));
+
+    // jobs that end up FAILED are moved away, so only the checks that
+    // don't look at them apply
+    mod dead_letter {
+        use super::*;
+
+        fn make(config: crate::QueueConfig) -> DeadLetter<InMemQueue> {
+            DeadLetter::pair(InMemQueue::new().with_config(config), InMemQueue::new())
+        }
+
+        #[test]
+        fn fifo_order() {
+            crate::conformance::fifo_order(make);
+        }
+
+        #[test]
+        fn ids() {
+            crate::conformance::ids(make);
+        }
+
+        #[test]
+        fn not_found() {
+            crate::conformance::not_found(make);
+        }
+
+        #[test]
+        fn concurrent_claims() {
+            crate::conformance::concurrent_claims(make);
+        }
+    }
+}
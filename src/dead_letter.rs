@@ -0,0 +1,253 @@
+use std::time::{Duration, SystemTime};
+
+use crate::{InMemQueue, Job, JobFilter, JobId, JobQueue, JobStatus, Page, QueueError, Stats};
+
+// Pairs a queue with a dead-letter queue. Jobs that end up FAILED in the
+// main queue are moved to the dead one, failure history included, where
+// they can be inspected and later re-driven.
+//
+// Moving a job between the two queues isn't atomic: it's first copied to the
+// destination and then removed from the source, so a crash in between can
+// leave it in both but never lose it.
+pub struct DeadLetter<Q, D = InMemQueue> {
+    main: Q,
+    dead: D,
+}
+
+impl<Q: JobQueue, D: JobQueue> DeadLetter<Q, D> {
+    pub fn pair(main: Q, dead: D) -> Self {
+        DeadLetter { main, dead }
+    }
+
+    pub fn main(&self) -> &Q {
+        &self.main
+    }
+
+    pub fn dead(&self) -> &D {
+        &self.dead
+    }
+
+    // Moves every FAILED job of the main queue to the dead-letter queue,
+    // returning how many were moved.
+    pub fn sweep(&self) -> Result<usize, QueueError> {
+        let failed: Vec<Job> = self
+            .main
+            .list()?
+            .into_iter()
+            .filter(|job| job.status == JobStatus::FAILED)
+            .collect();
+        let mut moved = 0;
+        for job in failed {
+            if self.bury(job)? {
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    }
+
+    pub fn dead_jobs(&self) -> Result<Vec<Job>, QueueError> {
+        self.dead.list()
+    }
+
+    pub fn inspect(&self, id_job: JobId) -> Result<Option<Job>, QueueError> {
+        self.dead.get(id_job)
+    }
+
+    // Puts a dead job back into the main queue as PENDING, with a fresh
+    // attempt count.
+    pub fn redrive(&self, id_job: JobId) -> Result<(), QueueError> {
+        let mut job = self.dead.get(id_job)?.ok_or(QueueError::NotFound(id_job))?;
+        job.revive()?;
+        self.main.enqueue(job)?;
+        self.dead.dequeue(id_job)
+    }
+
+    pub fn redrive_all(&self) -> Result<usize, QueueError> {
+        let dead = self.dead.list()?;
+        for job in &dead {
+            self.redrive(job.id)?;
+        }
+        Ok(dead.len())
+    }
+
+    // Moves `job` unless it's no longer FAILED in the main queue by then,
+    // say because it was retried and claimed since. Returns whether it was
+    // moved.
+    fn bury(&self, job: Job) -> Result<bool, QueueError> {
+        let id = job.id;
+        match self.dead.enqueue(job) {
+            // copied already by a move that didn't get to finish
+            Ok(_) | Err(QueueError::DuplicateId(_)) => {}
+            Err(e) => return Err(e),
+        }
+        match self.main.dequeue_if(id, JobStatus::FAILED) {
+            Ok(_) => Ok(true),
+            // moved by a concurrent sweep
+            Err(QueueError::NotFound(_)) => Ok(false),
+            Err(QueueError::WrongStatus { .. }) => match self.dead.dequeue(id) {
+                Ok(()) | Err(QueueError::NotFound(_)) => Ok(false),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// The pair can be used wherever a queue is expected: everything goes to the
+// main queue, and jobs exhausted by `fail` or `reap` are moved right away.
+impl<Q: JobQueue, D: JobQueue> JobQueue for DeadLetter<Q, D> {
+    fn new() -> Self {
+        DeadLetter::pair(Q::new(), D::new())
+    }
+
+    fn enqueue(&self, j: Job) -> Result<JobId, QueueError> {
+        self.main.enqueue(j)
+    }
+
+    fn get(&self, id_job: JobId) -> Result<Option<Job>, QueueError> {
+        self.main.get(id_job)
+    }
+
+    fn dequeue(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.main.dequeue(id_job)
+    }
+
+    fn dequeue_if(&self, id_job: JobId, status: JobStatus) -> Result<Job, QueueError> {
+        self.main.dequeue_if(id_job, status)
+    }
+
+    fn claim(&self) -> Result<Option<Job>, QueueError> {
+        self.main.claim()
+    }
+
+    fn claim_wait(&self, timeout: Duration) -> Result<Option<Job>, QueueError> {
+        self.main.claim_wait(timeout)
+    }
+
+    fn ack(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.main.ack(id_job)
+    }
+
+    fn fail(&self, id_job: JobId, reason: &str) -> Result<(), QueueError> {
+        self.main.fail(id_job, reason)?;
+        match self.main.get(id_job)? {
+            Some(job) if job.status == JobStatus::FAILED => self.bury(job).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    // FAILED jobs are in the dead queue.
+    fn retry(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.redrive(id_job)
+    }
+
+    fn heartbeat(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.main.heartbeat(id_job)
+    }
+
+    fn reap(&self) -> Result<usize, QueueError> {
+        let reaped = self.main.reap()?;
+        self.sweep()?;
+        Ok(reaped)
+    }
+
+    fn len(&self) -> Result<usize, QueueError> {
+        self.main.len()
+    }
+
+    fn list(&self) -> Result<Vec<Job>, QueueError> {
+        self.main.list()
+    }
+
+    fn now(&self) -> SystemTime {
+        self.main.now()
+    }
+
+    fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<Page, QueueError> {
+        self.main.query(filter, after, limit)
+    }
+
+    fn stats(&self) -> Result<Stats, QueueError> {
+        self.main.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backoff, QueueConfig, RetryPolicy};
+
+    #[test]
+    fn dead_letter_test() {
+        let main = InMemQueue::new().with_config(QueueConfig {
+            retry: RetryPolicy::new(2, Backoff::Fixed(Duration::ZERO)),
+            ..QueueConfig::default()
+        });
+        let q: DeadLetter<InMemQueue> = DeadLetter::pair(main, InMemQueue::new());
+        q.enqueue(Job::new(1, b"doomed")).unwrap();
+        q.enqueue(Job::new(2, b"fine")).unwrap();
+
+        q.claim().unwrap();
+        q.fail(1, "first").unwrap();
+        assert_eq!(q.len().unwrap(), 2); // still has an attempt left
+
+        q.claim().unwrap();
+        q.claim().unwrap();
+        q.fail(1, "second").unwrap();
+        q.ack(2).unwrap();
+        assert_eq!(q.len().unwrap(), 1);
+
+        let dead = q.dead_jobs().unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].errors(), ["first", "second"]);
+        assert!(q.inspect(1).unwrap().is_some());
+
+        q.redrive(1).unwrap();
+        assert!(q.dead_jobs().unwrap().is_empty());
+        let job = q.claim().unwrap().unwrap();
+        assert_eq!(job.get_id(), 1);
+        assert_eq!(job.attempts(), 1);
+        assert_eq!(job.errors().len(), 2);
+        assert!(matches!(q.redrive(1), Err(QueueError::NotFound(1))));
+    }
+
+    #[test]
+    fn sweep_test() {
+        let q: DeadLetter<InMemQueue> = DeadLetter::new();
+        for id in 1..=3 {
+            q.main().enqueue(Job::new(id, b"x")).unwrap();
+            q.main().claim().unwrap();
+            q.main().fail(id, "boom").unwrap();
+        }
+        assert_eq!(q.sweep().unwrap(), 3);
+        assert!(q.main().is_empty().unwrap());
+
+        assert_eq!(q.redrive_all().unwrap(), 3);
+        assert_eq!(q.len().unwrap(), 3);
+        assert!(q.dead().is_empty().unwrap());
+    }
+
+    #[test]
+    fn bury_revived_test() {
+        let q: DeadLetter<InMemQueue> = DeadLetter::new();
+        q.main().enqueue(Job::new(1, b"x")).unwrap();
+        q.main().claim().unwrap();
+        q.main().fail(1, "boom").unwrap();
+        let job = q.main().get(1).unwrap().unwrap();
+
+        // retried and claimed between a sweep's list and its move
+        q.main().retry(1).unwrap();
+        q.main().claim().unwrap();
+        assert!(!q.bury(job).unwrap());
+        assert_eq!(
+            q.main().get(1).unwrap().unwrap().get_status(),
+            &JobStatus::PICKED
+        );
+        assert!(q.dead().is_empty().unwrap());
+    }
+}
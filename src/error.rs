@@ -0,0 +1,94 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::PoisonError;
+
+use crate::{JobId, JobStatus, MAX_JOB_ID};
+
+#[derive(Debug)]
+pub enum QueueError {
+    // No job with this id is in the queue.
+    NotFound(JobId),
+    // A job with this id is already in the queue.
+    DuplicateId(JobId),
+    // A thread panicked while holding the queue lock.
+    Poisoned,
+    // The job isn't in a state that allows the requested operation.
+    InvalidTransition {
+        id: JobId,
+        from: JobStatus,
+        to: JobStatus,
+    },
+    // The queue already holds as many jobs as it's configured for.
+    Full(usize),
+    // A store has no queue with this name.
+    UnknownQueue(String),
+    // A store already has a queue with this name.
+    QueueExists(String),
+    // The job isn't in the status the operation expects it in.
+    WrongStatus {
+        id: JobId,
+        status: JobStatus,
+        expected: JobStatus,
+    },
+    // The id is above MAX_JOB_ID, or the queue ran out of ids.
+    IdOutOfRange(JobId),
+    // The storage behind the queue failed (I/O, SQL, ...).
+    Backend(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::NotFound(id) => write!(f, "Job with ID {} not found", id),
+            QueueError::DuplicateId(id) => write!(f, "Job with ID {} already exists", id),
+            QueueError::Poisoned => write!(f, "queue lock poisoned"),
+            QueueError::InvalidTransition { id, from, to } => {
+                write!(f, "Job with ID {} can't go from {:?} to {:?}", id, from, to)
+            }
+            QueueError::Full(capacity) => write!(f, "queue is full ({} jobs)", capacity),
+            QueueError::UnknownQueue(name) => write!(f, "Queue {:?} not found", name),
+            QueueError::QueueExists(name) => write!(f, "Queue {:?} already exists", name),
+            QueueError::WrongStatus {
+                id,
+                status,
+                expected,
+            } => write!(f, "Job with ID {} is {:?}, not {:?}", id, status, expected),
+            QueueError::IdOutOfRange(id) => {
+                write!(
+                    f,
+                    "Job ID {} is out of range, ids go up to {}",
+                    id, MAX_JOB_ID
+                )
+            }
+            QueueError::Backend(e) => write!(f, "backend error: {}", e),
+        }
+    }
+}
+
+impl Error for QueueError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            QueueError::Backend(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for QueueError {
+    fn from(_: PoisonError<T>) -> Self {
+        QueueError::Poisoned
+    }
+}
+
+impl From<std::io::Error> for QueueError {
+    fn from(e: std::io::Error) -> Self {
+        QueueError::Backend(Box::new(e))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for QueueError {
+    fn from(e: rusqlite::Error) -> Self {
+        QueueError::Backend(Box::new(e))
+    }
+}
@@ -0,0 +1,356 @@
+// An HTTP front end to a QueueStore, for people rather than workers:
+//
+//     GET    /queues                          names of the queues
+//     GET    /queues/{queue}/stats            counts per status and timings
+//     GET    /queues/{queue}/jobs             a page of jobs, filtered by the
+//                                             status, kind, after and limit
+//                                             query parameters
+//     GET    /queues/{queue}/jobs/{id}        one job
+//     POST   /queues/{queue}/jobs/{id}/retry  a FAILED job back to PENDING
+//     POST   /queues/{queue}/jobs/{id}/cancel removes a job not started yet
+//     DELETE /queues/{queue}/jobs/{id}        removes a job whatever its status
+//
+// Responses are JSON, errors `{"error": "..."}` with a matching status code.
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{Job, JobFilter, JobId, JobQueue, JobStatus, QueueError, QueueStore};
+
+// Page size when the request doesn't give a limit, and the most it may ask
+// for.
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+pub struct HttpApi<S> {
+    store: Arc<S>,
+}
+
+// What a request turned into, before it's written out.
+#[derive(Debug, PartialEq)]
+pub struct HttpReply {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl HttpReply {
+    fn ok(body: Value) -> Self {
+        HttpReply { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        HttpReply {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+}
+
+impl From<QueueError> for HttpReply {
+    fn from(e: QueueError) -> Self {
+        let status = match e {
+            QueueError::NotFound(_) | QueueError::UnknownQueue(_) => 404,
+            QueueError::DuplicateId(_)
+            | QueueError::QueueExists(_)
+            | QueueError::InvalidTransition { .. }
+            | QueueError::WrongStatus { .. } => 409,
+            QueueError::IdOutOfRange(_) => 400,
+            QueueError::Full(_) => 503,
+            QueueError::Poisoned | QueueError::Backend(_) => 500,
+        };
+        HttpReply::error(status, e)
+    }
+}
+
+impl<S: QueueStore> HttpApi<S> {
+    pub fn new(store: Arc<S>) -> Self {
+        HttpApi { store }
+    }
+
+    // Answers requests until the server shuts down, one at a time.
+    pub fn serve(&self, server: &tiny_http::Server) {
+        for request in server.incoming_requests() {
+            self.respond(request);
+        }
+    }
+
+    fn respond(&self, request: Request) {
+        let reply = self.handle(request.method(), request.url());
+        let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+        let response = Response::from_string(reply.body.to_string())
+            .with_status_code(reply.status)
+            .with_header(content_type);
+        // the client hanging up is its own problem
+        let _ = request.respond(response);
+    }
+
+    // Routes one request, `url` being the path and query string.
+    pub fn handle(&self, method: &Method, url: &str) -> HttpReply {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let segments: Vec<String> = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(decode)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let result = match (method, segments.as_slice()) {
+            (Method::Get, ["queues"]) => {
+                self.store.queues().map(|names| HttpReply::ok(json!(names)))
+            }
+            (Method::Get, ["queues", queue, "stats"]) => self
+                .store
+                .queue(queue)
+                .and_then(|q| q.stats())
+                .map(|stats| HttpReply::ok(stats.to_json())),
+            (Method::Get, ["queues", queue, "jobs"]) => match parse_query(query) {
+                Ok((filter, after, limit)) => self.list(queue, &filter, after, limit),
+                Err(reply) => return reply,
+            },
+            (method, ["queues", queue, "jobs", id, action @ ..]) => {
+                let Ok(id) = id.parse::<JobId>() else {
+                    return HttpReply::error(400, format!("invalid job id {:?}", id));
+                };
+                match (method, action) {
+                    (Method::Get, []) => self.job(queue, id),
+                    (Method::Post, ["retry"]) => self.retry(queue, id),
+                    (Method::Post, ["cancel"]) => self.cancel(queue, id),
+                    (Method::Delete, []) => self.delete(queue, id),
+                    (_, [] | ["retry"] | ["cancel"]) => {
+                        return HttpReply::error(405, "method not allowed")
+                    }
+                    _ => return HttpReply::error(404, "no such endpoint"),
+                }
+            }
+            (_, ["queues"] | ["queues", _, "stats" | "jobs"]) => {
+                return HttpReply::error(405, "method not allowed")
+            }
+            _ => return HttpReply::error(404, "no such endpoint"),
+        };
+        result.unwrap_or_else(HttpReply::from)
+    }
+
+    fn list(
+        &self,
+        queue: &str,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<HttpReply, QueueError> {
+        let page = self.store.queue(queue)?.query(filter, after, limit)?;
+        let jobs: Vec<Value> = page.jobs.iter().map(Job::to_json).collect();
+        Ok(HttpReply::ok(
+            json!({ "jobs": jobs, "next": page.next.map(|id| id.to_string()) }),
+        ))
+    }
+
+    fn job(&self, queue: &str, id: JobId) -> Result<HttpReply, QueueError> {
+        let job = self.store.queue(queue)?.get(id)?;
+        let job = job.ok_or(QueueError::NotFound(id))?;
+        Ok(HttpReply::ok(job.to_json()))
+    }
+
+    fn retry(&self, queue: &str, id: JobId) -> Result<HttpReply, QueueError> {
+        self.store.queue(queue)?.retry(id)?;
+        self.job(queue, id)
+    }
+
+    // Only PENDING jobs, a worker may already be busy with a PICKED one.
+    fn cancel(&self, queue: &str, id: JobId) -> Result<HttpReply, QueueError> {
+        let job = self
+            .store
+            .queue(queue)?
+            .dequeue_if(id, JobStatus::PENDING)?;
+        Ok(HttpReply::ok(job.to_json()))
+    }
+
+    fn delete(&self, queue: &str, id: JobId) -> Result<HttpReply, QueueError> {
+        self.store.queue(queue)?.dequeue(id)?;
+        Ok(HttpReply::ok(json!({ "deleted": id.to_string() })))
+    }
+}
+
+fn parse_query(query: &str) -> Result<(JobFilter, Option<JobId>, usize), HttpReply> {
+    let mut filter = JobFilter::new();
+    let mut after = None;
+    let mut limit = DEFAULT_LIMIT;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = decode(value);
+        let invalid = || HttpReply::error(400, format!("invalid {} {:?}", key, value));
+        match key {
+            "status" => filter = filter.with_status(parse_status(&value).ok_or_else(invalid)?),
+            "kind" => filter = filter.with_kind(&value),
+            "after" => after = Some(value.parse().map_err(|_| invalid())?),
+            "limit" => {
+                limit = value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or_else(invalid)?
+                    .min(MAX_LIMIT)
+            }
+            _ => {
+                return Err(HttpReply::error(
+                    400,
+                    format!("unknown parameter {:?}", key),
+                ))
+            }
+        }
+    }
+    Ok((filter, after, limit))
+}
+
+fn parse_status(s: &str) -> Option<JobStatus> {
+    match s.to_ascii_uppercase().as_str() {
+        "PENDING" => Some(JobStatus::PENDING),
+        "PICKED" => Some(JobStatus::PICKED),
+        "PROCESSED" => Some(JobStatus::PROCESSED),
+        "FAILED" => Some(JobStatus::FAILED),
+        _ => None,
+    }
+}
+
+// Percent-decoding, with '+' as a space.
+fn decode(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = bytes.clone().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(byte) if hex.len() == 2 => {
+                        out.push(byte);
+                        bytes.nth(1);
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemStore, QueueConfig};
+
+    fn api() -> (HttpApi<InMemStore>, crate::InMemQueue) {
+        let store = InMemStore::new();
+        let q = store.create_queue("mail", QueueConfig::default()).unwrap();
+        (HttpApi::new(Arc::new(store)), q)
+    }
+
+    #[test]
+    fn list_and_stats_test() {
+        let (api, q) = api();
+        for kind in ["welcome", "digest", "welcome"] {
+            q.enqueue(Job::anonymous(b"hi").with_kind(kind)).unwrap();
+        }
+        q.claim().unwrap();
+
+        let reply = api.handle(&Method::Get, "/queues");
+        assert_eq!(reply.body, json!(["mail"]));
+
+        let reply = api.handle(&Method::Get, "/queues/mail/jobs?kind=welcome&limit=1");
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body["jobs"][0]["id"], "1");
+        assert_eq!(reply.body["jobs"][0]["status"], "PICKED");
+        assert_eq!(reply.body["jobs"][0]["payload"], "hi");
+        assert_eq!(reply.body["next"], "1");
+        let reply = api.handle(&Method::Get, "/queues/mail/jobs?kind=welcome&after=1");
+        assert_eq!(reply.body["jobs"][0]["id"], "3");
+        assert_eq!(reply.body["next"], Value::Null);
+
+        let reply = api.handle(&Method::Get, "/queues/mail/jobs?status=pending");
+        assert_eq!(reply.body["jobs"].as_array().unwrap().len(), 2);
+
+        let reply = api.handle(&Method::Get, "/queues/mail/stats");
+        assert_eq!(reply.body["pending"], 2);
+        assert_eq!(reply.body["picked"], 1);
+        assert_eq!(reply.body["total"], 3);
+    }
+
+    #[test]
+    fn retry_cancel_delete_test() {
+        let (api, q) = api();
+        let a = q.enqueue(Job::anonymous(b"a")).unwrap();
+        let b = q.enqueue(Job::anonymous(b"b")).unwrap();
+        q.claim().unwrap();
+        q.fail(a, "smtp down").unwrap();
+        let post = |id, action| {
+            api.handle(
+                &Method::Post,
+                &format!("/queues/mail/jobs/{}/{}", id, action),
+            )
+        };
+
+        let reply = post(a, "retry");
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body["status"], "PENDING");
+        assert_eq!(reply.body["attempts"], 0);
+        assert_eq!(reply.body["errors"], json!(["smtp down"]));
+        assert_eq!(post(a, "retry").status, 409);
+
+        // a retried job keeps its place in the queue
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), a);
+        // and can't be retried while a worker has it
+        assert_eq!(post(a, "retry").status, 409);
+        assert_eq!(post(a, "cancel").status, 409);
+        assert_eq!(post(b, "cancel").status, 200);
+        assert!(q.get(b).unwrap().is_none());
+
+        let reply = api.handle(&Method::Delete, &format!("/queues/mail/jobs/{}", a));
+        assert_eq!(reply.status, 200);
+        assert!(q.is_empty().unwrap());
+        let reply = api.handle(&Method::Get, &format!("/queues/mail/jobs/{}", a));
+        assert_eq!(reply.status, 404);
+    }
+
+    #[test]
+    fn errors_test() {
+        let (api, _) = api();
+        assert_eq!(api.handle(&Method::Get, "/queues/nope/stats").status, 404);
+        assert_eq!(api.handle(&Method::Get, "/queues/mail/jobs/x").status, 400);
+        assert_eq!(
+            api.handle(&Method::Get, "/queues/mail/jobs?limit=0").status,
+            400
+        );
+        assert_eq!(
+            api.handle(&Method::Get, "/queues/mail/jobs?status=odd")
+                .status,
+            400
+        );
+        assert_eq!(api.handle(&Method::Post, "/queues/mail/stats").status, 405);
+        assert_eq!(api.handle(&Method::Get, "/elsewhere").status, 404);
+        assert_eq!(decode("a%20b+c%2"), "a b c%2");
+    }
+
+    #[test]
+    fn serve_test() {
+        use std::io::{Read, Write};
+
+        let (api, q) = api();
+        q.enqueue(Job::anonymous(b"x")).unwrap();
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        std::thread::spawn(move || api.serve(&server));
+
+        let mut conn = std::net::TcpStream::connect(addr).unwrap();
+        conn.write_all(b"GET /queues/mail/stats HTTP/1.0\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+        assert!(response.contains("application/json"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(body).unwrap()["pending"], 1);
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::QueueError;
+
+// Job ids are assigned by the queue on enqueue, unless the caller picked one
+// already. 0 is never assigned and stands for "no id yet".
+pub type JobId = u64;
+
+// The highest id a job can have, so that ids fit the signed 64-bit
+// integers SQLite stores.
+pub const MAX_JOB_ID: JobId = i64::MAX as JobId;
+
+// How a queue comes up with ids for the jobs enqueued without one. Either
+// way ids only grow, so they are never reused within a queue, even for
+// jobs that were dequeued since.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdStrategy {
+    // 1, 2, 3, ...
+    #[default]
+    Sequential,
+    // Milliseconds since the epoch in the high 48 bits and a counter in the
+    // low 16, like the first half of a ULID: ids sort by creation time and
+    // tell when the job was enqueued, while staying unique within a queue.
+    TimeOrdered,
+}
+
+impl IdStrategy {
+    // The id to give the next job, `last` being the highest one the queue
+    // has seen so far. Fails with IdOutOfRange once ids ran past MAX_JOB_ID,
+    // which only a caller-supplied id close to it can cause.
+    pub fn next(self, last: JobId, now: SystemTime) -> Result<JobId, QueueError> {
+        let after = last.checked_add(1);
+        let next = match self {
+            IdStrategy::Sequential => after,
+            IdStrategy::TimeOrdered => {
+                let millis = now
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0);
+                after.map(|after| (millis << 16).max(after))
+            }
+        };
+        next.filter(|&id| id <= MAX_JOB_ID)
+            .ok_or(QueueError::IdOutOfRange(last.saturating_add(1)))
+    }
+}
+
+// Fails with IdOutOfRange for an id no queue can hold.
+pub(crate) fn check_id(id: JobId) -> Result<JobId, QueueError> {
+    if id > MAX_JOB_ID {
+        return Err(QueueError::IdOutOfRange(id));
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_test() {
+        assert_eq!(
+            IdStrategy::Sequential.next(0, SystemTime::now()).unwrap(),
+            1
+        );
+        assert_eq!(
+            IdStrategy::Sequential.next(41, SystemTime::now()).unwrap(),
+            42
+        );
+
+        let first = IdStrategy::TimeOrdered.next(0, SystemTime::now()).unwrap();
+        let second = IdStrategy::TimeOrdered
+            .next(first, SystemTime::now())
+            .unwrap();
+        assert!(second > first);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        assert!(millis - (second >> 16) < 1000);
+
+        // a caller-supplied id from the future doesn't make ids go back
+        assert_eq!(
+            IdStrategy::TimeOrdered
+                .next(MAX_JOB_ID - 1, SystemTime::now())
+                .unwrap(),
+            MAX_JOB_ID
+        );
+
+        // no wrapping around to 0 once ids run out
+        for strategy in [IdStrategy::Sequential, IdStrategy::TimeOrdered] {
+            for last in [MAX_JOB_ID, u64::MAX] {
+                assert!(matches!(
+                    strategy.next(last, SystemTime::now()),
+                    Err(QueueError::IdOutOfRange(_))
+                ));
+            }
+        }
+        assert!(check_id(MAX_JOB_ID).is_ok());
+        assert!(check_id(MAX_JOB_ID + 1).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+// The JSON shapes of jobs and stats shown to people, by the HTTP API and
+// the command-line tool. Times are in milliseconds, since the Unix epoch for
+// instants. Job ids are strings: time-ordered ones are past 2^53, which is
+// as far as JavaScript and many other JSON parsers read integers exactly.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::{Job, Stats};
+
+fn millis(t: &SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn duration_millis(d: Option<Duration>) -> Option<u64> {
+    d.map(|d| d.as_millis() as u64)
+}
+
+impl Job {
+    // The payload is only included when it's text.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id.to_string(),
+            "status": format!("{:?}", self.status),
+            "kind": self.kind,
+            "priority": self.priority,
+            "attempts": self.attempts,
+            "expiries": self.expiries,
+            "errors": self.errors,
+            "created_at": millis(&self.timestamp),
+            "heartbeat_at": millis(&self.heartbeat),
+            "run_at": self.run_at.as_ref().map(millis),
+            "retry": self.retry.as_ref().map(ToString::to_string),
+            "codec": self.codec,
+            "payload": std::str::from_utf8(&self.payload).ok(),
+            "payload_size": self.payload.len(),
+        })
+    }
+}
+
+impl Stats {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "pending": self.pending,
+            "picked": self.picked,
+            "processed": self.processed,
+            "failed": self.failed,
+            "total": self.total(),
+            "oldest_pending_ms": duration_millis(self.oldest_pending),
+            "avg_pick_ms": duration_millis(self.avg_pick),
+            "avg_completion_ms": duration_millis(self.avg_completion),
+        })
+    }
+}
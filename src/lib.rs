@@ -1,14 +1,82 @@
-use std::{collections::VecDeque, time::SystemTime};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+#[cfg(feature = "async")]
+mod async_queue;
+mod clock;
+#[cfg(feature = "serde")]
+mod codec;
+pub mod conformance;
+mod dead_letter;
+mod error;
+#[cfg(feature = "http")]
+mod http;
+mod id;
+#[cfg(any(feature = "http", feature = "cli"))]
+mod json;
+mod memory;
+mod net;
+mod query;
+mod reaper;
+mod registry;
+mod resp;
+mod retry;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod store;
+mod wal;
+mod wire;
+mod worker;
+
+#[cfg(feature = "async")]
+pub use async_queue::{AsyncInMemQueue, AsyncJobQueue, Blocking};
+pub use clock::{Clock, MockClock, SystemClock};
+#[cfg(feature = "serde")]
+pub use codec::{Bincode, Codec, CodecError, Json, MsgPack};
+pub use dead_letter::DeadLetter;
+pub use error::QueueError;
+#[cfg(feature = "http")]
+pub use http::{HttpApi, HttpReply};
+pub use id::{IdStrategy, JobId, MAX_JOB_ID};
+pub use memory::InMemQueue;
+pub use net::{RemoteQueue, Server, DEFAULT_ADDR};
+pub use query::{JobFilter, Page};
+pub use reaper::Reaper;
+pub use registry::Registry;
+pub use resp::RespServer;
+pub use retry::{Backoff, RetryPolicy};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteQueue, SqliteStore};
+pub use stats::Stats;
+pub use store::{InMemStore, QueueStore};
+pub use wal::{Durability, WalQueue};
+pub use worker::{Handler, WorkerConfig, WorkerPool};
+
+#[derive(Clone, Debug)]
 pub struct Job {
-    id: u32,
+    id: JobId,
     status: JobStatus,
     payload: Vec<u8>,
     timestamp: SystemTime,
     heartbeat: SystemTime,
+    errors: Vec<String>,
+    expiries: u32,
+    attempts: u32,
+    retry: Option<RetryPolicy>,
+    run_at: Option<SystemTime>,
+    priority: i32,
+    kind: String,
+    codec: Option<String>,
+    // Jobs made with `new` are stamped by the queue's clock on enqueue,
+    // `delay` being what with_delay asked for; until then their times come
+    // from the system clock.
+    stamped: bool,
+    delay: Option<Duration>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum JobStatus {
     PENDING,
     PICKED,
@@ -16,30 +84,129 @@ pub enum JobStatus {
     FAILED,
 }
 
-pub struct InMemQueue {
-    jobs: Arc<Mutex<VecDeque<Job>>>,
+// How long a PICKED job may go without a heartbeat before the reaper hands
+// it back to PENDING, and how many times that may happen before the job is
+// considered FAILED instead. `capacity` bounds the number of jobs the queue
+// holds, enqueue fails with QueueError::Full past it. `retry` applies to
+// every job that doesn't carry its own policy, and `ids` to every job
+// enqueued without an id. `clock` is what the queue takes the time from.
+#[derive(Clone, Debug)]
+pub struct QueueConfig {
+    pub lease: Duration,
+    pub max_expiries: u32,
+    pub capacity: Option<usize>,
+    pub retry: RetryPolicy,
+    pub ids: IdStrategy,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            lease: Duration::from_secs(30),
+            max_expiries: 3,
+            capacity: None,
+            retry: RetryPolicy::default(),
+            ids: IdStrategy::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
 }
 
 impl Job {
-    pub fn new(id: u32, payload: &[u8]) -> Self {
-        let now = SystemTime::now();
+    // A job with an id of the caller's choosing, which enqueue rejects if the
+    // queue already has it. An id of 0 lets the queue pick one. The job is
+    // created when it's enqueued, as told by the queue's clock.
+    pub fn new(id: JobId, payload: &[u8]) -> Self {
+        Job {
+            stamped: false,
+            ..Job::new_at(id, payload, SystemTime::now())
+        }
+    }
+
+    // Like new, for a job created at `now` whichever queue it goes to.
+    pub fn new_at(id: JobId, payload: &[u8], now: SystemTime) -> Self {
         Job {
             id,
             status: JobStatus::PENDING,
             payload: payload.to_vec(),
             timestamp: now,
             heartbeat: now,
+            errors: Vec::new(),
+            expiries: 0,
+            attempts: 0,
+            retry: None,
+            run_at: None,
+            priority: 0,
+            kind: String::new(),
+            codec: None,
+            stamped: true,
+            delay: None,
         }
     }
 
-    pub fn update_heartbeat(&mut self) {
-        self.heartbeat = SystemTime::now();
+    // A job the queue will give an id to when it's enqueued.
+    pub fn anonymous(payload: &[u8]) -> Self {
+        Job::new(0, payload)
+    }
+
+    // Names what the job is about ("email", "thumbnail", ...) so that a
+    // Registry can route it to the right handler.
+    pub fn with_kind(mut self, kind: &str) -> Self {
+        self.kind = kind.to_string();
+        self
+    }
+
+    // Keeps the job from being claimed before `at`.
+    pub fn with_run_at(mut self, at: SystemTime) -> Self {
+        self.run_at = Some(at);
+        self.delay = None;
+        self
+    }
+
+    // Keeps the job from being claimed until `delay` after it was created.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.run_at = Some(self.timestamp + delay);
+        self.delay = Some(delay);
+        self
+    }
+
+    // Jobs with a higher priority are claimed first, the default is 0.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // Overrides the queue's retry policy for this job.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    // Stamps the heartbeat of a job held outside a queue, `now` being the
+    // queue's time (JobQueue::now). Jobs in a queue go through
+    // JobQueue::heartbeat.
+    pub fn update_heartbeat(&mut self, now: SystemTime) {
+        self.heartbeat = now;
     }
 
-    pub fn get_id(&self) -> u32 {
+    pub fn get_id(&self) -> JobId {
         self.id
     }
 
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    // The codec the payload was encoded with, None for raw bytes.
+    pub fn codec(&self) -> Option<&str> {
+        self.codec.as_deref()
+    }
+
     pub fn get_status(&self) -> &JobStatus {
         &self.status
     }
@@ -51,56 +218,269 @@ impl Job {
     pub fn heartbeat(&self) -> &SystemTime {
         &self.heartbeat
     }
-}
 
-pub trait JobQueue {
-    fn new() -> Self;
-    fn enqueue(&self, j: Job) -> Result<(), String>;
-    fn get(&self, id_job: u32) -> Option<Job>;
-    fn dequeue(&self, id_job: u32) -> Result<(), String>;
-    fn len(&self) -> usize;
-}
+    // Every reason the job has been failed with, oldest first.
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.errors.last().map(String::as_str)
+    }
+
+    // How many times the lease on this job ran out while it was PICKED.
+    pub fn expiries(&self) -> u32 {
+        self.expiries
+    }
+
+    // How many times the job has been claimed.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    // The job can't be claimed before this time.
+    pub fn run_at(&self) -> Option<&SystemTime> {
+        self.run_at.as_ref()
+    }
 
-impl JobQueue for InMemQueue {
-    fn new() -> Self {
-        InMemQueue {
-            jobs: Arc::new(Mutex::new(VecDeque::new())),
+    // Dates a job made with `new` from the clock of the queue it's being
+    // enqueued into.
+    fn stamp(&mut self, now: SystemTime) {
+        if self.stamped {
+            return;
         }
+        self.timestamp = now;
+        self.heartbeat = now;
+        if let Some(delay) = self.delay.take() {
+            self.run_at = Some(now + delay);
+        }
+        self.stamped = true;
+    }
+
+    fn lease_expired(&self, now: SystemTime, lease: Duration) -> bool {
+        self.status == JobStatus::PICKED
+            && now.duration_since(self.heartbeat).unwrap_or_default() > lease
+    }
+
+    fn transition(&mut self, to: JobStatus) -> Result<(), QueueError> {
+        if !self.status.can_become(to) {
+            return Err(QueueError::InvalidTransition {
+                id: self.id,
+                from: self.status,
+                to,
+            });
+        }
+        self.status = to;
+        Ok(())
     }
 
-    fn enqueue(&self, j: Job) -> Result<(), String> {
-        let mut queue = self.jobs.lock().map_err(|_| "failed to lock".to_string())?;
-        queue.push_back(j);
+    fn pick(&mut self, now: SystemTime) -> Result<(), QueueError> {
+        self.transition(JobStatus::PICKED)?;
+        self.attempts += 1;
+        self.heartbeat = now;
         Ok(())
     }
 
-    fn get(&self, id_job: u32) -> Option<Job> {
-        let queue = self.jobs.lock().expect("Failed to lock the queue");
-        if let Some(pos) = queue.iter().position(|job| job.id == id_job) {
-            return queue.get(pos).cloned();
+    fn ack(&mut self) -> Result<(), QueueError> {
+        self.transition(JobStatus::PROCESSED)
+    }
+
+    // Puts the job back to PENDING if `policy` (or the job's own one) allows
+    // another attempt, FAILED otherwise.
+    fn fail(
+        &mut self,
+        reason: &str,
+        policy: &RetryPolicy,
+        now: SystemTime,
+    ) -> Result<(), QueueError> {
+        // a FAILED job with attempts left must not come back through here
+        if self.status != JobStatus::PICKED {
+            return Err(QueueError::InvalidTransition {
+                id: self.id,
+                from: self.status,
+                to: JobStatus::FAILED,
+            });
+        }
+        let policy = self.retry.as_ref().unwrap_or(policy);
+        if !policy.should_retry(self.attempts) {
+            return self.give_up(reason);
         }
-        None
+        let run_at = now + policy.delay(self.attempts);
+        self.transition(JobStatus::PENDING)?;
+        self.errors.push(reason.to_string());
+        self.run_at = Some(run_at);
+        Ok(())
     }
 
-    fn dequeue(&self, id_job: u32) -> Result<(), String> {
-        let mut queue = self.jobs.lock().expect("Failed to lock queue");
-        if let Some(pos) = queue.iter().position(|job| job.id == id_job) {
-            queue.remove(pos).unwrap();
-            return Ok(());
+    // Makes a FAILED job claimable again as if it was new, but keeps its
+    // failure history. This is the only way out of FAILED, so it's not in
+    // `can_become`.
+    fn revive(&mut self) -> Result<(), QueueError> {
+        if self.status != JobStatus::FAILED {
+            return Err(QueueError::WrongStatus {
+                id: self.id,
+                status: self.status,
+                expected: JobStatus::FAILED,
+            });
         }
+        self.status = JobStatus::PENDING;
+        self.attempts = 0;
+        self.expiries = 0;
+        self.run_at = None;
+        Ok(())
+    }
 
-        Err(format!("Job with ID {} not found", id_job))
+    fn give_up(&mut self, reason: &str) -> Result<(), QueueError> {
+        self.transition(JobStatus::FAILED)?;
+        self.errors.push(reason.to_string());
+        Ok(())
     }
 
-    fn len(&self) -> usize {
-        let queue = self.jobs.lock().expect("Failed to lock queue");
-        queue.len()
+    fn touch(&mut self, now: SystemTime) -> Result<(), QueueError> {
+        // only PICKED jobs hold a lease
+        if self.status != JobStatus::PICKED {
+            return Err(QueueError::InvalidTransition {
+                id: self.id,
+                from: self.status,
+                to: JobStatus::PICKED,
+            });
+        }
+        self.heartbeat = now;
+        Ok(())
+    }
+
+    fn expire(&mut self, max_expiries: u32) -> Result<(), QueueError> {
+        self.expiries += 1;
+        if self.expiries > max_expiries {
+            let reason = format!("lease expired {} times", self.expiries);
+            return self.give_up(&reason);
+        }
+        self.transition(JobStatus::PENDING)
+    }
+}
+
+impl JobStatus {
+    pub fn can_become(self, to: JobStatus) -> bool {
+        matches!(
+            (self, to),
+            (JobStatus::PENDING, JobStatus::PICKED)
+                | (JobStatus::PICKED, JobStatus::PROCESSED)
+                | (JobStatus::PICKED, JobStatus::FAILED)
+                | (JobStatus::PICKED, JobStatus::PENDING)
+        )
+    }
+}
+
+pub trait JobQueue {
+    fn new() -> Self;
+    // Adds the job to the queue and returns its id, assigning one first if
+    // the job has none. Fails with DuplicateId if the queue already holds a
+    // job with the id the caller picked.
+    fn enqueue(&self, j: Job) -> Result<JobId, QueueError>;
+    fn get(&self, id_job: JobId) -> Result<Option<Job>, QueueError>;
+    fn dequeue(&self, id_job: JobId) -> Result<(), QueueError>;
+    // Removes the job and returns it, only if it's in `status` at that very
+    // moment; fails with WrongStatus otherwise.
+    fn dequeue_if(&self, id_job: JobId, status: JobStatus) -> Result<Job, QueueError>;
+    // Atomically takes the highest priority PENDING job that is due (the
+    // oldest one among equals), marks it PICKED, counts the attempt and
+    // stamps its heartbeat. Returns None when nothing is pending.
+    fn claim(&self) -> Result<Option<Job>, QueueError>;
+    // Like claim, but when nothing is available waits up to `timeout` for a
+    // job to be enqueued or become due. This default polls, backing off up
+    // to 100ms between tries; backends that can be notified override it.
+    fn claim_wait(&self, timeout: Duration) -> Result<Option<Job>, QueueError> {
+        // too far out to be an Instant is as good as forever
+        let deadline = Instant::now().checked_add(timeout);
+        let mut pause = Duration::from_millis(1);
+        loop {
+            if let Some(job) = self.claim()? {
+                return Ok(Some(job));
+            }
+            let left = deadline.map_or(Duration::MAX, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            if left.is_zero() {
+                return Ok(None);
+            }
+            thread::sleep(pause.min(left));
+            pause = (pause * 2).min(Duration::from_millis(100));
+        }
+    }
+    // Marks a PICKED job as PROCESSED.
+    fn ack(&self, id_job: JobId) -> Result<(), QueueError>;
+    // Records why a PICKED job failed. The job goes back to PENDING, not
+    // claimable before its backoff delay, while its retry policy allows more
+    // attempts, and to FAILED after the last one.
+    fn fail(&self, id_job: JobId, reason: &str) -> Result<(), QueueError>;
+    // Puts a FAILED job back to PENDING with a fresh attempt count, keeping
+    // its errors.
+    fn retry(&self, id_job: JobId) -> Result<(), QueueError>;
+    // Extends the lease on a PICKED job.
+    fn heartbeat(&self, id_job: JobId) -> Result<(), QueueError>;
+    // Hands PICKED jobs whose lease ran out back to PENDING, or to FAILED
+    // once they expired more than `max_expiries` times. Returns how many
+    // jobs were touched.
+    fn reap(&self) -> Result<usize, QueueError>;
+    fn len(&self) -> Result<usize, QueueError>;
+    // Every job in the queue, in the order they were enqueued.
+    fn list(&self) -> Result<Vec<Job>, QueueError>;
+    // What time it is for the queue, the system time unless it has a Clock
+    // of its own. The defaults of query and stats go by it.
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+    // Up to `limit` jobs matching `filter` with an id greater than `after`,
+    // in id order. This default filters `list`; backends that can do better
+    // override it.
+    fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<Page, QueueError> {
+        let now = self.now();
+        let mut jobs: Vec<Job> = self
+            .list()?
+            .into_iter()
+            .filter(|job| after.is_none_or(|after| job.id > after) && filter.matches(job, now))
+            .collect();
+        jobs.sort_by_key(|job| job.id);
+        jobs.truncate(limit.saturating_add(1));
+        Ok(Page::from_jobs(jobs, after, limit))
+    }
+    // Counts per status and timings. This default counts what `list`
+    // returns and leaves the averages to backends that keep track of them.
+    fn stats(&self) -> Result<Stats, QueueError> {
+        let now = self.now();
+        let mut stats = Stats::default();
+        for job in self.list()? {
+            stats.add(job.status, 1);
+            if job.status == JobStatus::PENDING {
+                let age = now.duration_since(job.timestamp).unwrap_or_default();
+                stats.oldest_pending = stats.oldest_pending.max(Some(age));
+            }
+        }
+        Ok(stats)
+    }
+
+    fn is_empty(&self) -> Result<bool, QueueError> {
+        Ok(self.len()? == 0)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
 
     #[test]
     fn enqueue_test() {
@@ -108,21 +488,14 @@ mod tests {
         let j = Job::new(1, b"Hello, World!");
 
         // Then i need to create a Queue
-        let mut q = InMemQueue::new();
+        let q = InMemQueue::new();
 
-        match q.enqueue(j) {
-            Ok(_) => println!("Job enqueueed successfully."),
-            Err(e) => println!("Error {}", e),
-        }
+        assert_eq!(q.enqueue(j).unwrap(), 1);
 
         // Now i need to retrieve the Job again
-        let res_job = q.get(1);
-
-        // Here we should understand how to assert with Options
-        match res_job {
-            Some(x) => assert_eq!(x.id, 1),
-            None => println!("NOOOOOO!"),
-        }
+        let res_job = q.get(1).unwrap().expect("job should be in the queue");
+        assert_eq!(res_job.id, 1);
+        assert_eq!(res_job.payload(), b"Hello, World!");
     }
     #[test]
     fn dequeue_test() {
@@ -130,20 +503,211 @@ mod tests {
         let j = Job::new(1, b"Hello, World!");
 
         // Then i need to create a Queue
-        let mut q = InMemQueue::new();
+        let q = InMemQueue::new();
 
-        match q.enqueue(j) {
-            Ok(_) => println!("Job enqueueed successfully."),
-            Err(e) => println!("Error {}", e),
-        }
+        q.enqueue(j).unwrap();
+        assert_eq!(q.len().unwrap(), 1); // contains 1 element
 
-        assert_eq!(q.len(), 1); // contains 1 element
+        q.dequeue(1).unwrap();
+        assert_eq!(q.len().unwrap(), 0); // contains no element
+        assert!(matches!(q.dequeue(1), Err(QueueError::NotFound(1))));
+    }
 
-        match q.dequeue(1) {
-            Ok(_) => println!("Job dequeued successfully."),
-            Err(e) => println!("Error {}", e),
-        }
+    #[test]
+    fn claim_test() {
+        let q = InMemQueue::new();
+        q.enqueue(Job::new(1, b"first")).unwrap();
+        q.enqueue(Job::new(2, b"second")).unwrap();
+
+        let job = q.claim().unwrap().unwrap();
+        assert_eq!(job.get_id(), 1);
+        assert_eq!(job.get_status(), &JobStatus::PICKED);
+        assert_eq!(q.get(1).unwrap().unwrap().get_status(), &JobStatus::PICKED);
+
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+        assert!(q.claim().unwrap().is_none());
+    }
+
+    #[test]
+    fn ack_fail_test() {
+        let q = InMemQueue::new();
+        q.enqueue(Job::new(1, b"ok")).unwrap();
+        q.enqueue(Job::new(2, b"broken")).unwrap();
+
+        // nothing has been picked yet
+        assert!(q.ack(1).is_err());
+        assert!(q.fail(2, "too early").is_err());
+
+        q.claim().unwrap();
+        q.claim().unwrap();
+        q.ack(1).unwrap();
+        q.fail(2, "disk full").unwrap();
+
+        assert_eq!(
+            q.get(1).unwrap().unwrap().get_status(),
+            &JobStatus::PROCESSED
+        );
+        let failed = q.get(2).unwrap().unwrap();
+        assert_eq!(failed.get_status(), &JobStatus::FAILED);
+        assert_eq!(failed.last_error(), Some("disk full"));
+
+        // finished jobs can't change outcome
+        assert!(matches!(
+            q.fail(1, "late"),
+            Err(QueueError::InvalidTransition {
+                id: 1,
+                from: JobStatus::PROCESSED,
+                to: JobStatus::FAILED,
+            })
+        ));
+        assert!(q.ack(2).is_err());
+        assert!(matches!(q.ack(3), Err(QueueError::NotFound(3))));
+    }
+
+    #[test]
+    fn reap_test() {
+        let clock = MockClock::default();
+        let q = InMemQueue::new().with_config(QueueConfig {
+            lease: Duration::from_secs(30),
+            max_expiries: 1,
+            clock: Arc::new(clock.clone()),
+            ..QueueConfig::default()
+        });
+        q.enqueue(Job::new(1, b"slow")).unwrap();
+        assert!(q.heartbeat(1).is_err()); // not picked yet
+
+        q.claim().unwrap();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(q.reap().unwrap(), 0); // not quite
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(q.reap().unwrap(), 1);
+        let job = q.get(1).unwrap().unwrap();
+        assert_eq!(job.get_status(), &JobStatus::PENDING);
+        assert_eq!(job.expiries(), 1);
+
+        // a heartbeat keeps the lease alive
+        q.claim().unwrap();
+        clock.advance(Duration::from_secs(20));
+        q.heartbeat(1).unwrap();
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(q.reap().unwrap(), 0);
+
+        // second expiry is one too many
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(q.reap().unwrap(), 1);
+        let job = q.get(1).unwrap().unwrap();
+        assert_eq!(job.get_status(), &JobStatus::FAILED);
+        assert_eq!(job.last_error(), Some("lease expired 2 times"));
+    }
+
+    #[test]
+    fn retry_test() {
+        let clock = MockClock::default();
+        let q = InMemQueue::new().with_config(QueueConfig {
+            retry: RetryPolicy::new(2, Backoff::Fixed(Duration::from_secs(10))),
+            clock: Arc::new(clock.clone()),
+            ..QueueConfig::default()
+        });
+        q.enqueue(Job::new(1, b"flaky")).unwrap();
+        q.enqueue(Job::new(2, b"hopeless").with_retry(RetryPolicy::default()))
+            .unwrap();
+
+        q.claim().unwrap();
+        q.fail(1, "timeout").unwrap();
+        let job = q.get(1).unwrap().unwrap();
+        assert_eq!(job.get_status(), &JobStatus::PENDING);
+        assert_eq!(job.attempts(), 1);
+        assert!(job.run_at().is_some());
+
+        // job 1 is backing off, the per-job policy of 2 doesn't retry
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+        q.fail(2, "bad input").unwrap();
+        assert_eq!(q.get(2).unwrap().unwrap().get_status(), &JobStatus::FAILED);
+        assert!(q.claim().unwrap().is_none());
+
+        clock.advance(Duration::from_secs(10));
+        let job = q.claim().unwrap().unwrap();
+        assert_eq!(job.get_id(), 1);
+        assert_eq!(job.attempts(), 2);
+        q.fail(1, "timeout again").unwrap();
+        let job = q.get(1).unwrap().unwrap();
+        assert_eq!(job.get_status(), &JobStatus::FAILED);
+        assert_eq!(job.errors(), ["timeout", "timeout again"]);
+    }
+
+    #[test]
+    fn failed_is_final_test() {
+        let clock = MockClock::default();
+        let q = InMemQueue::new().with_config(QueueConfig {
+            max_expiries: 0,
+            retry: RetryPolicy::new(5, Backoff::Fixed(Duration::ZERO)),
+            clock: Arc::new(clock.clone()),
+            ..QueueConfig::default()
+        });
+        q.enqueue(Job::new(1, b"lost")).unwrap();
+        q.claim().unwrap();
+        clock.advance(q.config().lease * 2);
+        assert_eq!(q.reap().unwrap(), 1);
+
+        // attempts left, but only retry brings it back
+        assert!(matches!(
+            q.fail(1, "late"),
+            Err(QueueError::InvalidTransition { .. })
+        ));
+        assert_eq!(q.get(1).unwrap().unwrap().get_status(), &JobStatus::FAILED);
+
+        q.retry(1).unwrap();
+        q.claim().unwrap();
+        assert!(matches!(
+            q.retry(1),
+            Err(QueueError::WrongStatus {
+                status: JobStatus::PICKED,
+                expected: JobStatus::FAILED,
+                ..
+            })
+        ));
+        assert!(q.claim().unwrap().is_none());
+    }
+
+    #[test]
+    fn capacity_test() {
+        let q = InMemQueue::new().with_config(QueueConfig {
+            capacity: Some(1),
+            ..QueueConfig::default()
+        });
+        q.enqueue(Job::new(1, b"fits")).unwrap();
+        assert!(matches!(
+            q.enqueue(Job::new(2, b"doesn't")),
+            Err(QueueError::Full(1))
+        ));
+        assert_eq!(q.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn concurrent_claim_test() {
+        let q = Arc::new(InMemQueue::new());
+        let ids: Vec<JobId> = (0..100)
+            .map(|_| q.enqueue(Job::anonymous(b"work")).unwrap())
+            .collect();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let q = Arc::clone(&q);
+                std::thread::spawn(move || {
+                    let mut claimed = Vec::new();
+                    while let Some(job) = q.claim().unwrap() {
+                        claimed.push(job.get_id());
+                    }
+                    claimed
+                })
+            })
+            .collect();
 
-        assert_eq!(q.len(), 0); // contains no element
+        let mut all: Vec<JobId> = workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        all.sort();
+        assert_eq!(all, ids);
     }
 }
@@ -0,0 +1,663 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::id::check_id;
+use crate::stats::Timing;
+use crate::{Job, JobFilter, JobId, JobQueue, JobStatus, Page, QueueConfig, QueueError, Stats};
+
+// Arc and Mutex around the queue State give safe concurrent queue
+// operations. Nothing is persisted. Threads blocked in claim_wait sleep on
+// `available`, which is notified whenever a job may have become claimable.
+// Clones are handles to the same queue.
+#[derive(Clone)]
+pub struct InMemQueue {
+    jobs: Arc<Mutex<State>>,
+    available: Arc<Condvar>,
+    config: QueueConfig,
+}
+
+// Jobs are stored by `seq`, a counter that follows insertion order. PENDING
+// jobs are also indexed, so that claiming never scans the whole queue:
+// `scheduled` orders the ones that aren't due yet by their run_at, and
+// `ready` holds the due ones, highest priority first and in insertion order
+// within a priority. Jobs move from the first to the second as their time
+// comes. `ids` maps job ids to their seq, in id order for queries, and
+// `last_id` is the highest id ever enqueued, which new ids are derived from.
+//
+// For stats, `counts` follows how many jobs are in each status, `pending`
+// orders the PENDING ones by age, and `picks` and `completions` time every
+// pick and ack as they happen.
+#[derive(Default)]
+pub(crate) struct State {
+    jobs: BTreeMap<u64, Job>,
+    next_seq: u64,
+    ids: BTreeMap<JobId, u64>,
+    last_id: JobId,
+    ready: BTreeSet<(Reverse<i32>, u64)>,
+    scheduled: BTreeSet<(SystemTime, u64)>,
+    counts: [usize; 4],
+    pending: BTreeSet<(SystemTime, u64)>,
+    picks: Timing,
+    completions: Timing,
+}
+
+impl State {
+    pub(crate) fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub(crate) fn jobs(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.values()
+    }
+
+    pub(crate) fn find(&self, id_job: JobId) -> Option<u64> {
+        self.ids.get(&id_job).copied()
+    }
+
+    pub(crate) fn lookup(&self, id_job: JobId) -> Option<&Job> {
+        self.find(id_job).and_then(|seq| self.job(seq))
+    }
+
+    pub(crate) fn job(&self, seq: u64) -> Option<&Job> {
+        self.jobs.get(&seq)
+    }
+
+    pub(crate) fn insert(&mut self, job: Job) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.ids.insert(job.id, seq);
+        self.last_id = self.last_id.max(job.id);
+        self.counts[job.status as usize] += 1;
+        self.jobs.insert(seq, job);
+        self.index(seq);
+        seq
+    }
+
+    // Stores `job` as is, replacing the one with the same id if any. Unlike
+    // going through `modify`, this doesn't count as a pick or an ack in the
+    // stats.
+    pub(crate) fn put(&mut self, job: Job) {
+        let Some(seq) = self.find(job.id) else {
+            self.insert(job);
+            return;
+        };
+        self.unindex(seq);
+        if let Some(old) = self.jobs.insert(seq, job) {
+            self.counts[old.status as usize] -= 1;
+        }
+        self.counts[self.jobs[&seq].status as usize] += 1;
+        self.index(seq);
+    }
+
+    // The highest id ever enqueued.
+    pub(crate) fn last_id(&self) -> JobId {
+        self.last_id
+    }
+
+    pub(crate) fn raise_last_id(&mut self, id: JobId) {
+        self.last_id = self.last_id.max(id);
+    }
+
+    pub(crate) fn remove(&mut self, seq: u64) -> Option<Job> {
+        self.unindex(seq);
+        let job = self.jobs.remove(&seq)?;
+        self.ids.remove(&job.id);
+        self.counts[job.status as usize] -= 1;
+        Some(job)
+    }
+
+    // Applies `f` to the job at `now`, keeping the PENDING indexes and the
+    // stats in sync with whatever it does to the job status and run_at.
+    pub(crate) fn modify<F, T>(&mut self, seq: u64, now: SystemTime, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Job) -> T,
+    {
+        self.unindex(seq);
+        let job = self.jobs.get_mut(&seq)?;
+        let before = job.status;
+        let res = f(job);
+        let after = job.status;
+        if before != after {
+            self.counts[before as usize] -= 1;
+            self.counts[after as usize] += 1;
+            match after {
+                JobStatus::PICKED if before == JobStatus::PENDING => {
+                    let waited = job.heartbeat.duration_since(job.timestamp);
+                    self.picks.record(waited.unwrap_or_default());
+                }
+                JobStatus::PROCESSED => {
+                    let took = now.duration_since(job.timestamp);
+                    self.completions.record(took.unwrap_or_default());
+                }
+                _ => {}
+            }
+        }
+        self.index(seq);
+        Some(res)
+    }
+
+    pub(crate) fn update<F>(
+        &mut self,
+        id_job: JobId,
+        now: SystemTime,
+        f: F,
+    ) -> Result<(), QueueError>
+    where
+        F: FnOnce(&mut Job) -> Result<(), QueueError>,
+    {
+        let seq = self.find(id_job).ok_or(QueueError::NotFound(id_job))?;
+        self.modify(seq, now, f)
+            .unwrap_or(Err(QueueError::NotFound(id_job)))
+    }
+
+    // The PENDING job with the highest priority among those due at `now`,
+    // the oldest one on ties.
+    pub(crate) fn next_due(&mut self, now: SystemTime) -> Option<u64> {
+        while let Some(&(at, seq)) = self.scheduled.first() {
+            if at > now {
+                break;
+            }
+            self.scheduled.pop_first();
+            if let Some(job) = self.jobs.get(&seq) {
+                self.ready.insert((Reverse(job.priority), seq));
+            }
+        }
+        self.ready.first().map(|&(_, seq)| seq)
+    }
+
+    // When the next job that isn't due yet will be.
+    pub(crate) fn next_scheduled(&self) -> Option<SystemTime> {
+        self.scheduled.first().map(|&(at, _)| at)
+    }
+
+    pub(crate) fn enqueue(&mut self, job: Job, config: &QueueConfig) -> Result<JobId, QueueError> {
+        let job = self.admit(job, config)?;
+        let id = job.id;
+        self.insert(job);
+        Ok(id)
+    }
+
+    // Checks that `job` can be enqueued and gives it an id if it has none,
+    // without inserting it yet.
+    pub(crate) fn admit(&self, mut job: Job, config: &QueueConfig) -> Result<Job, QueueError> {
+        if let Some(capacity) = config.capacity {
+            if self.len() >= capacity {
+                return Err(QueueError::Full(capacity));
+            }
+        }
+        let now = config.clock.now();
+        job.stamp(now);
+        if job.id == 0 {
+            job.id = config.ids.next(self.last_id, now)?;
+        } else if self.ids.contains_key(&check_id(job.id)?) {
+            return Err(QueueError::DuplicateId(job.id));
+        }
+        Ok(job)
+    }
+
+    pub(crate) fn dequeue(&mut self, id_job: JobId) -> Result<(), QueueError> {
+        let seq = self.find(id_job).ok_or(QueueError::NotFound(id_job))?;
+        self.remove(seq);
+        Ok(())
+    }
+
+    // The seq of the job, provided it's in `status`.
+    pub(crate) fn find_in(&self, id_job: JobId, status: JobStatus) -> Result<u64, QueueError> {
+        let seq = self.find(id_job).ok_or(QueueError::NotFound(id_job))?;
+        match self.jobs.get(&seq) {
+            Some(job) if job.status == status => Ok(seq),
+            Some(job) => Err(QueueError::WrongStatus {
+                id: id_job,
+                status: job.status,
+                expected: status,
+            }),
+            None => Err(QueueError::NotFound(id_job)),
+        }
+    }
+
+    // Returns how many jobs had their lease run out.
+    pub(crate) fn reap(
+        &mut self,
+        now: SystemTime,
+        config: &QueueConfig,
+    ) -> Result<usize, QueueError> {
+        let expired = self.expired(now, config);
+        for &id in &expired {
+            self.update(id, now, |job| job.expire(config.max_expiries))?;
+        }
+        Ok(expired.len())
+    }
+
+    // The ids of the jobs whose lease ran out at `now`.
+    pub(crate) fn expired(&self, now: SystemTime, config: &QueueConfig) -> Vec<JobId> {
+        self.jobs
+            .values()
+            .filter(|job| job.lease_expired(now, config.lease))
+            .map(|job| job.id)
+            .collect()
+    }
+
+    pub(crate) fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+        now: SystemTime,
+    ) -> Page {
+        let from = after.map_or(0, |after| after.saturating_add(1));
+        let jobs = self
+            .ids
+            .range(from..)
+            .filter_map(|(_, seq)| self.jobs.get(seq))
+            .filter(|job| filter.matches(job, now))
+            .take(limit.saturating_add(1))
+            .cloned()
+            .collect();
+        Page::from_jobs(jobs, after, limit)
+    }
+
+    pub(crate) fn stats(&self, now: SystemTime) -> Stats {
+        let mut stats = Stats {
+            oldest_pending: self
+                .pending
+                .first()
+                .map(|&(at, _)| now.duration_since(at).unwrap_or_default()),
+            avg_pick: self.picks.average(),
+            avg_completion: self.completions.average(),
+            ..Stats::default()
+        };
+        for status in [
+            JobStatus::PENDING,
+            JobStatus::PICKED,
+            JobStatus::PROCESSED,
+            JobStatus::FAILED,
+        ] {
+            stats.add(status, self.counts[status as usize]);
+        }
+        stats
+    }
+
+    pub(crate) fn claim(&mut self, now: SystemTime) -> Result<Option<Job>, QueueError> {
+        let Some(seq) = self.next_due(now) else {
+            return Ok(None);
+        };
+        self.modify(seq, now, |job| {
+            job.pick(now)?;
+            Ok(job.clone())
+        })
+        .transpose()
+    }
+
+    fn index(&mut self, seq: u64) {
+        let Some(job) = self.jobs.get(&seq) else {
+            return;
+        };
+        if job.status != JobStatus::PENDING {
+            return;
+        }
+        self.pending.insert((job.timestamp, seq));
+        match job.run_at {
+            Some(at) => {
+                self.scheduled.insert((at, seq));
+            }
+            None => {
+                self.ready.insert((Reverse(job.priority), seq));
+            }
+        }
+    }
+
+    fn unindex(&mut self, seq: u64) {
+        let Some(job) = self.jobs.get(&seq) else {
+            return;
+        };
+        self.ready.remove(&(Reverse(job.priority), seq));
+        self.pending.remove(&(job.timestamp, seq));
+        if let Some(at) = job.run_at {
+            self.scheduled.remove(&(at, seq));
+        }
+    }
+}
+
+impl InMemQueue {
+    pub fn with_config(mut self, config: QueueConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    fn update<F>(&self, id_job: JobId, f: F) -> Result<(), QueueError>
+    where
+        F: FnOnce(&mut Job, SystemTime) -> Result<(), QueueError>,
+    {
+        let now = self.config.clock.now();
+        self.jobs.lock()?.update(id_job, now, |job| f(job, now))
+    }
+
+    fn notify(&self) {
+        self.available.notify_all();
+    }
+}
+
+impl JobQueue for InMemQueue {
+    fn new() -> Self {
+        InMemQueue {
+            jobs: Arc::new(Mutex::new(State::default())),
+            available: Arc::new(Condvar::new()),
+            config: QueueConfig::default(),
+        }
+    }
+
+    fn enqueue(&self, j: Job) -> Result<JobId, QueueError> {
+        let id = self.jobs.lock()?.enqueue(j, &self.config)?;
+        self.notify();
+        Ok(id)
+    }
+
+    fn get(&self, id_job: JobId) -> Result<Option<Job>, QueueError> {
+        Ok(self.jobs.lock()?.lookup(id_job).cloned())
+    }
+
+    fn dequeue(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.jobs.lock()?.dequeue(id_job)
+    }
+
+    fn dequeue_if(&self, id_job: JobId, status: JobStatus) -> Result<Job, QueueError> {
+        let mut state = self.jobs.lock()?;
+        let seq = state.find_in(id_job, status)?;
+        state.remove(seq).ok_or(QueueError::NotFound(id_job))
+    }
+
+    fn claim(&self) -> Result<Option<Job>, QueueError> {
+        self.jobs.lock()?.claim(self.config.clock.now())
+    }
+
+    fn claim_wait(&self, timeout: Duration) -> Result<Option<Job>, QueueError> {
+        // too far out to be an Instant is as good as forever
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.jobs.lock()?;
+        loop {
+            let now = self.config.clock.now();
+            if let Some(job) = state.claim(now)? {
+                return Ok(Some(job));
+            }
+            let left = deadline.map_or(Duration::MAX, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            if left.is_zero() {
+                return Ok(None);
+            }
+            // nobody notifies when a scheduled job becomes due, so don't
+            // sleep past that
+            let wait = match state.next_scheduled() {
+                Some(at) => at.duration_since(now).unwrap_or_default().min(left),
+                None => left,
+            };
+            state = self.available.wait_timeout(state, wait)?.0;
+        }
+    }
+
+    fn ack(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, _| job.ack())
+    }
+
+    fn fail(&self, id_job: JobId, reason: &str) -> Result<(), QueueError> {
+        self.update(id_job, |job, now| job.fail(reason, &self.config.retry, now))?;
+        self.notify();
+        Ok(())
+    }
+
+    fn retry(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, _| job.revive())?;
+        self.notify();
+        Ok(())
+    }
+
+    fn heartbeat(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, now| job.touch(now))
+    }
+
+    fn reap(&self) -> Result<usize, QueueError> {
+        let reaped = self
+            .jobs
+            .lock()?
+            .reap(self.config.clock.now(), &self.config)?;
+        if reaped > 0 {
+            self.notify();
+        }
+        Ok(reaped)
+    }
+
+    fn len(&self) -> Result<usize, QueueError> {
+        let state = self.jobs.lock()?;
+        Ok(state.len())
+    }
+
+    fn list(&self) -> Result<Vec<Job>, QueueError> {
+        let state = self.jobs.lock()?;
+        Ok(state.jobs().cloned().collect())
+    }
+
+    fn now(&self) -> SystemTime {
+        self.config.clock.now()
+    }
+
+    fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<Page, QueueError> {
+        let state = self.jobs.lock()?;
+        Ok(state.query(filter, after, limit, self.config.clock.now()))
+    }
+
+    fn stats(&self) -> Result<Stats, QueueError> {
+        Ok(self.jobs.lock()?.stats(self.config.clock.now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Clock;
+
+    #[test]
+    fn scheduled_test() {
+        let q = InMemQueue::new();
+        q.enqueue(Job::new(1, b"later").with_delay(Duration::from_millis(50)))
+            .unwrap();
+        q.enqueue(Job::new(2, b"now")).unwrap();
+        q.enqueue(Job::new(3, b"in the past").with_run_at(SystemTime::UNIX_EPOCH))
+            .unwrap();
+
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 3);
+        assert!(q.claim().unwrap().is_none());
+        assert_eq!(q.get(1).unwrap().unwrap().get_status(), &JobStatus::PENDING);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 1);
+    }
+
+    #[test]
+    fn priority_test() {
+        let q = InMemQueue::new();
+        q.enqueue(Job::new(1, b"low").with_priority(-1)).unwrap();
+        q.enqueue(Job::new(2, b"normal")).unwrap();
+        q.enqueue(Job::new(3, b"urgent").with_priority(10)).unwrap();
+        q.enqueue(Job::new(4, b"normal too")).unwrap();
+        q.enqueue(
+            Job::new(5, b"urgent but later")
+                .with_priority(10)
+                .with_delay(Duration::from_secs(60)),
+        )
+        .unwrap();
+
+        let order: Vec<JobId> = std::iter::from_fn(|| q.claim().unwrap())
+            .map(|job| job.get_id())
+            .collect();
+        assert_eq!(order, [3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn claim_wait_test() {
+        let q = Arc::new(InMemQueue::new());
+        let waiter = {
+            let q = Arc::clone(&q);
+            // as good as forever, not an overflow
+            std::thread::spawn(move || q.claim_wait(Duration::MAX))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        let start = Instant::now();
+        q.enqueue(Job::new(1, b"wake up")).unwrap();
+        let job = waiter.join().unwrap().unwrap().unwrap();
+        assert_eq!(job.get_id(), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // scheduled jobs wake the waiter up on their own
+        q.enqueue(Job::new(2, b"soon").with_delay(Duration::from_millis(50)))
+            .unwrap();
+        let job = q.claim_wait(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(job.get_id(), 2);
+
+        let start = Instant::now();
+        assert!(q.claim_wait(Duration::from_millis(30)).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn ids_test() {
+        let q = InMemQueue::new();
+        assert_eq!(q.enqueue(Job::anonymous(b"a")).unwrap(), 1);
+        assert_eq!(q.enqueue(Job::new(10, b"b")).unwrap(), 10);
+        assert_eq!(q.enqueue(Job::anonymous(b"c")).unwrap(), 11);
+        assert!(matches!(
+            q.enqueue(Job::new(10, b"again")),
+            Err(QueueError::DuplicateId(10))
+        ));
+        assert_eq!(q.get(10).unwrap().unwrap().payload(), b"b");
+
+        // ids of dequeued jobs aren't handed out again
+        q.dequeue(11).unwrap();
+        assert_eq!(q.enqueue(Job::anonymous(b"d")).unwrap(), 12);
+        q.enqueue(Job::new(11, b"by hand")).unwrap();
+
+        let q = InMemQueue::new().with_config(QueueConfig {
+            ids: crate::IdStrategy::TimeOrdered,
+            ..QueueConfig::default()
+        });
+        let first = q.enqueue(Job::anonymous(b"a")).unwrap();
+        let second = q.enqueue(Job::anonymous(b"b")).unwrap();
+        assert!(first > 1 << 16 && second > first);
+    }
+
+    #[test]
+    fn query_test() {
+        let q = InMemQueue::new();
+        for i in 0..10 {
+            let kind = if i % 2 == 0 { "email" } else { "sms" };
+            q.enqueue(Job::anonymous(b"x").with_kind(kind)).unwrap();
+        }
+        q.claim().unwrap();
+
+        let emails = JobFilter::new().with_kind("email");
+        let page = q.query(&emails, None, 3).unwrap();
+        let ids: Vec<JobId> = page.jobs.iter().map(Job::get_id).collect();
+        assert_eq!(ids, [1, 3, 5]);
+        assert_eq!(page.next, Some(5));
+
+        // jobs enqueued meanwhile don't shift the pages
+        q.dequeue(3).unwrap();
+        q.enqueue(Job::anonymous(b"x").with_kind("email")).unwrap();
+        let page = q.query(&emails, page.next, 3).unwrap();
+        let ids: Vec<JobId> = page.jobs.iter().map(Job::get_id).collect();
+        assert_eq!(ids, [7, 9, 11]);
+        assert_eq!(page.next, None);
+
+        let pending = JobFilter::new()
+            .with_status(JobStatus::PENDING)
+            .with_kind("email");
+        assert_eq!(q.query(&pending, None, 100).unwrap().jobs.len(), 4);
+    }
+
+    #[test]
+    fn stats_test() {
+        let q = InMemQueue::new();
+        assert_eq!(q.stats().unwrap(), Stats::default());
+        for _ in 0..4 {
+            q.enqueue(Job::anonymous(b"x")).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        let first = q.claim().unwrap().unwrap().get_id();
+        let second = q.claim().unwrap().unwrap().get_id();
+        q.ack(first).unwrap();
+        q.fail(second, "nope").unwrap();
+        q.dequeue(3).unwrap();
+
+        let stats = q.stats().unwrap();
+        assert_eq!(
+            (stats.pending, stats.picked, stats.processed, stats.failed),
+            (1, 0, 1, 1)
+        );
+        assert_eq!(stats.total(), q.len().unwrap());
+        assert!(stats.oldest_pending.unwrap() >= Duration::from_millis(20));
+        assert!(stats.avg_pick.unwrap() >= Duration::from_millis(20));
+        assert!(stats.avg_completion.unwrap() >= stats.avg_pick.unwrap());
+
+        q.claim().unwrap();
+        let stats = q.stats().unwrap();
+        assert_eq!(stats.oldest_pending, None);
+        assert_eq!(stats.count(JobStatus::PICKED), 1);
+    }
+
+    #[test]
+    fn index_follows_status_test() {
+        let q = InMemQueue::new();
+        q.enqueue(Job::new(1, b"a")).unwrap();
+        q.enqueue(Job::new(2, b"b").with_delay(Duration::from_secs(60)))
+            .unwrap();
+        q.dequeue(1).unwrap();
+        assert!(q.claim().unwrap().is_none());
+
+        let state = q.jobs.lock().unwrap();
+        assert!(state.ready.is_empty());
+        assert_eq!(state.scheduled.len(), 1);
+    }
+
+    #[test]
+    fn mock_clock_test() {
+        let clock = crate::MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+        let q = InMemQueue::new().with_config(QueueConfig {
+            lease: Duration::from_secs(30),
+            max_expiries: 1,
+            retry: crate::RetryPolicy::new(2, crate::Backoff::Fixed(Duration::from_secs(10))),
+            clock: Arc::new(clock.clone()),
+            ..QueueConfig::default()
+        });
+        let now = clock.now();
+        q.enqueue(Job::new_at(1, b"later", now).with_delay(Duration::from_secs(60)))
+            .unwrap();
+        q.enqueue(Job::new_at(2, b"flaky", now)).unwrap();
+
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+        q.fail(2, "boom").unwrap();
+        assert!(q.claim().unwrap().is_none());
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(q.reap().unwrap(), 0);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(q.reap().unwrap(), 1);
+        assert_eq!(q.get(2).unwrap().unwrap().expiries(), 1);
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 1);
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+    }
+}
@@ -0,0 +1,643 @@
+// A JobQueue served over TCP, and the client for it.
+//
+// Every message, both ways, is a frame: a u32 little-endian length and that
+// many bytes. A request is an opcode byte followed by its arguments, the
+// response a status byte, 0 followed by the result or 1 followed by an
+// error. Integers are fixed-width little-endian, strings are a u32 length
+// and UTF-8 bytes, an optional value is a 0 byte or a 1 byte and the value,
+// and jobs use the same encoding as the write-ahead log (see wire.rs).
+//
+//     opcode            arguments             result
+//     1  ENQUEUE        job, stamp            id: u64
+//     2  GET            id: u64               optional job
+//     3  DEQUEUE        id: u64               -
+//     4  CLAIM          -                     optional job
+//     5  CLAIM_WAIT     timeout millis: u64   optional job
+//     6  ACK            id: u64               -
+//     7  FAIL           id: u64, reason       -
+//     8  HEARTBEAT      id: u64               -
+//     9  REAP           -                     count: u64
+//     10 LEN            -                     count: u64
+//     11 LIST           -                     count: u32, that many jobs
+//     12 STATS          -                     pending, picked, processed,
+//                                             failed: u64, then oldest
+//                                             pending, average pick and
+//                                             average completion as
+//                                             optional nanos: u64
+//     13 RETRY          id: u64               -
+//     14 DEQUEUE_IF     id: u64, status: u8   job
+//     15 QUERY          filter, optional      optional next: u64,
+//                       after: u64,           count: u32, that many jobs
+//                       limit: u64
+//
+// An error is a code byte and its details:
+//
+//     1 NotFound id: u64             5 Full capacity: u64
+//     2 DuplicateId id: u64          6 UnknownQueue name
+//     3 Poisoned                     7 QueueExists name
+//     4 InvalidTransition id: u64,   8 Backend message
+//       from: u8, to: u8             9 IdOutOfRange id: u64
+//                                    10 WrongStatus id: u64, status: u8,
+//                                       expected: u8
+//
+// A filter is an optional status: u8, an optional kind, optional created
+// after and created before as nanos since the Unix epoch: u64, and an
+// optional heartbeat age in nanos: u64.
+//
+// The stamp after an enqueued job is a 0 byte for a job with times of its
+// own, or a 1 byte and an optional delay in nanos: u64 for one made with
+// Job::new, which the server dates by its clock.
+//
+// Statuses are 0 PENDING, 1 PICKED, 2 PROCESSED, 3 FAILED. A malformed
+// request gets a Backend error and the connection stays open.
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::wire::{
+    decode_job, encode_job, from_nanos, get_opt_string, get_status, get_string, get_u32, get_u64,
+    get_u8, invalid, put_bytes, put_opt_str, to_nanos,
+};
+use crate::{Job, JobFilter, JobId, JobQueue, JobStatus, Page, QueueError, Stats};
+
+// Where foxtail-server listens unless told otherwise.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+// Frames larger than this are refused rather than allocated.
+const MAX_FRAME: usize = 64 << 20;
+
+const ENQUEUE: u8 = 1;
+const GET: u8 = 2;
+const DEQUEUE: u8 = 3;
+const CLAIM: u8 = 4;
+const CLAIM_WAIT: u8 = 5;
+const ACK: u8 = 6;
+const FAIL: u8 = 7;
+const HEARTBEAT: u8 = 8;
+const REAP: u8 = 9;
+const LEN: u8 = 10;
+const LIST: u8 = 11;
+const STATS: u8 = 12;
+const RETRY: u8 = 13;
+const DEQUEUE_IF: u8 = 14;
+const QUERY: u8 = 15;
+
+const OK: u8 = 0;
+const ERR: u8 = 1;
+
+// Serves a queue to any number of RemoteQueue clients, one thread per
+// connection.
+pub struct Server<Q> {
+    queue: Arc<Q>,
+}
+
+impl<Q> Server<Q>
+where
+    Q: JobQueue + Send + Sync + 'static,
+{
+    pub fn new(queue: Arc<Q>) -> Self {
+        Server { queue }
+    }
+
+    // Accepts connections until the listener fails.
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                // the client went away before we got to it
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => continue,
+                Err(e) => return Err(e),
+            };
+            let queue = Arc::clone(&self.queue);
+            thread::spawn(move || {
+                // a broken connection only concerns its client
+                let _ = handle(&*queue, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle<Q: JobQueue>(queue: &Q, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    while let Some(request) = read_frame(&mut stream)? {
+        let mut response = vec![OK];
+        if let Err(e) = dispatch(queue, &request, &mut response) {
+            response.clear();
+            response.push(ERR);
+            put_error(&mut response, &e);
+        }
+        write_frame(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+fn dispatch<Q: JobQueue>(queue: &Q, mut req: &[u8], out: &mut Vec<u8>) -> Result<(), QueueError> {
+    let req = &mut req;
+    match get_u8(req)? {
+        ENQUEUE => {
+            let mut job = decode_job(req)?;
+            if get_u8(req)? != 0 {
+                job.stamped = false;
+                job.delay = match get_u8(req)? {
+                    0 => None,
+                    _ => Some(Duration::from_nanos(get_u64(req)?)),
+                };
+            }
+            let id = queue.enqueue(job)?;
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        GET => put_opt_job(out, queue.get(get_u64(req)?)?),
+        DEQUEUE => queue.dequeue(get_u64(req)?)?,
+        CLAIM => put_opt_job(out, queue.claim()?),
+        CLAIM_WAIT => {
+            let timeout = Duration::from_millis(get_u64(req)?);
+            put_opt_job(out, queue.claim_wait(timeout)?);
+        }
+        ACK => queue.ack(get_u64(req)?)?,
+        FAIL => {
+            let id = get_u64(req)?;
+            queue.fail(id, &get_string(req)?)?;
+        }
+        HEARTBEAT => queue.heartbeat(get_u64(req)?)?,
+        RETRY => queue.retry(get_u64(req)?)?,
+        DEQUEUE_IF => {
+            let id = get_u64(req)?;
+            encode_job(&queue.dequeue_if(id, get_status(req)?)?, out);
+        }
+        REAP => out.extend_from_slice(&(queue.reap()? as u64).to_le_bytes()),
+        LEN => out.extend_from_slice(&(queue.len()? as u64).to_le_bytes()),
+        LIST => {
+            let jobs = queue.list()?;
+            out.extend_from_slice(&(jobs.len() as u32).to_le_bytes());
+            for job in &jobs {
+                encode_job(job, out);
+            }
+        }
+        STATS => put_stats(out, &queue.stats()?),
+        QUERY => {
+            let filter = get_filter(req)?;
+            let after = get_opt_u64(req)?;
+            let limit = get_u64(req)?.min(usize::MAX as u64) as usize;
+            let page = queue.query(&filter, after, limit)?;
+            put_opt_u64(out, page.next);
+            out.extend_from_slice(&(page.jobs.len() as u32).to_le_bytes());
+            for job in &page.jobs {
+                encode_job(job, out);
+            }
+        }
+        other => return Err(invalid(&format!("unknown opcode {}", other)).into()),
+    }
+    Ok(())
+}
+
+// A queue living in a foxtail-server. Connections are opened as needed and
+// kept for reuse, so threads sharing a RemoteQueue don't wait on each other;
+// one that fails is dropped and the call returns a Backend error.
+pub struct RemoteQueue {
+    addr: String,
+    idle: Mutex<Vec<TcpStream>>,
+}
+
+impl RemoteQueue {
+    // Connects right away, so that a wrong address shows up here rather than
+    // on first use.
+    pub fn connect(addr: &str) -> Result<Self, QueueError> {
+        let q = RemoteQueue {
+            addr: addr.to_string(),
+            idle: Mutex::new(Vec::new()),
+        };
+        let conn = q.open()?;
+        q.idle.lock()?.push(conn);
+        Ok(q)
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    fn open(&self) -> io::Result<TcpStream> {
+        let conn = TcpStream::connect(&self.addr)?;
+        conn.set_nodelay(true)?;
+        Ok(conn)
+    }
+
+    // Sends one request and returns the body of a successful response.
+    fn call(&self, request: &[u8]) -> Result<Vec<u8>, QueueError> {
+        let idle = self.idle.lock()?.pop();
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => self.open()?,
+        };
+        write_frame(&mut conn, request)?;
+        let response =
+            read_frame(&mut conn)?.ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+        self.idle.lock()?.push(conn);
+
+        let mut body = &response[..];
+        match get_u8(&mut body)? {
+            OK => Ok(body.to_vec()),
+            _ => Err(get_error(&mut body)?),
+        }
+    }
+
+    fn call_id(&self, op: u8, id: JobId) -> Result<Vec<u8>, QueueError> {
+        let mut req = vec![op];
+        req.extend_from_slice(&id.to_le_bytes());
+        self.call(&req)
+    }
+}
+
+impl JobQueue for RemoteQueue {
+    // A client for a server on DEFAULT_ADDR, which connects on first use.
+    fn new() -> Self {
+        RemoteQueue {
+            addr: DEFAULT_ADDR.to_string(),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn enqueue(&self, j: Job) -> Result<JobId, QueueError> {
+        let mut req = vec![ENQUEUE];
+        encode_job(&j, &mut req);
+        match (j.stamped, j.delay) {
+            (true, _) => req.push(0),
+            (false, None) => req.extend_from_slice(&[1, 0]),
+            (false, Some(delay)) => {
+                req.extend_from_slice(&[1, 1]);
+                req.extend_from_slice(&(delay.as_nanos() as u64).to_le_bytes());
+            }
+        }
+        Ok(get_u64(&mut &self.call(&req)?[..])?)
+    }
+
+    fn get(&self, id_job: JobId) -> Result<Option<Job>, QueueError> {
+        Ok(get_opt_job(&mut &self.call_id(GET, id_job)?[..])?)
+    }
+
+    fn dequeue(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.call_id(DEQUEUE, id_job).map(drop)
+    }
+
+    fn dequeue_if(&self, id_job: JobId, status: JobStatus) -> Result<Job, QueueError> {
+        let mut req = vec![DEQUEUE_IF];
+        req.extend_from_slice(&id_job.to_le_bytes());
+        req.push(status as u8);
+        Ok(decode_job(&mut &self.call(&req)?[..])?)
+    }
+
+    fn claim(&self) -> Result<Option<Job>, QueueError> {
+        Ok(get_opt_job(&mut &self.call(&[CLAIM])?[..])?)
+    }
+
+    // Waits on the server, which is notified of new jobs, instead of
+    // polling it.
+    fn claim_wait(&self, timeout: Duration) -> Result<Option<Job>, QueueError> {
+        let millis = timeout.as_millis().min(u64::MAX as u128) as u64;
+        let mut req = vec![CLAIM_WAIT];
+        req.extend_from_slice(&millis.to_le_bytes());
+        Ok(get_opt_job(&mut &self.call(&req)?[..])?)
+    }
+
+    fn ack(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.call_id(ACK, id_job).map(drop)
+    }
+
+    fn fail(&self, id_job: JobId, reason: &str) -> Result<(), QueueError> {
+        let mut req = vec![FAIL];
+        req.extend_from_slice(&id_job.to_le_bytes());
+        put_bytes(&mut req, reason.as_bytes());
+        self.call(&req).map(drop)
+    }
+
+    fn retry(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.call_id(RETRY, id_job).map(drop)
+    }
+
+    fn heartbeat(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.call_id(HEARTBEAT, id_job).map(drop)
+    }
+
+    fn reap(&self) -> Result<usize, QueueError> {
+        Ok(get_u64(&mut &self.call(&[REAP])?[..])? as usize)
+    }
+
+    fn len(&self) -> Result<usize, QueueError> {
+        Ok(get_u64(&mut &self.call(&[LEN])?[..])? as usize)
+    }
+
+    fn list(&self) -> Result<Vec<Job>, QueueError> {
+        let response = self.call(&[LIST])?;
+        let mut body = &response[..];
+        let count = get_u32(&mut body)?;
+        let jobs = (0..count)
+            .map(|_| decode_job(&mut body))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(jobs)
+    }
+
+    fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<Page, QueueError> {
+        let mut req = vec![QUERY];
+        put_filter(&mut req, filter);
+        put_opt_u64(&mut req, after);
+        req.extend_from_slice(&(limit as u64).to_le_bytes());
+        let response = self.call(&req)?;
+        let mut body = &response[..];
+        let next = get_opt_u64(&mut body)?;
+        let jobs = (0..get_u32(&mut body)?)
+            .map(|_| decode_job(&mut body))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Page { jobs, next })
+    }
+
+    fn stats(&self) -> Result<Stats, QueueError> {
+        Ok(get_stats(&mut &self.call(&[STATS])?[..])?)
+    }
+}
+
+// None once the peer closed the connection between frames.
+fn read_frame(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid(&format!("frame of {} bytes", len)));
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+fn write_frame(stream: &mut TcpStream, body: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(body);
+    stream.write_all(&frame)
+}
+
+fn put_opt_job(buf: &mut Vec<u8>, job: Option<Job>) {
+    match job {
+        Some(job) => {
+            buf.push(1);
+            encode_job(&job, buf);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn get_opt_job(buf: &mut &[u8]) -> io::Result<Option<Job>> {
+    match get_u8(buf)? {
+        0 => Ok(None),
+        _ => decode_job(buf).map(Some),
+    }
+}
+
+fn put_opt_duration(buf: &mut Vec<u8>, d: Option<Duration>) {
+    match d {
+        Some(d) => {
+            buf.push(1);
+            buf.extend_from_slice(&(d.as_nanos().min(u64::MAX as u128) as u64).to_le_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn get_opt_duration(buf: &mut &[u8]) -> io::Result<Option<Duration>> {
+    match get_u8(buf)? {
+        0 => Ok(None),
+        _ => Ok(Some(Duration::from_nanos(get_u64(buf)?))),
+    }
+}
+
+fn put_opt_u64(buf: &mut Vec<u8>, n: Option<u64>) {
+    match n {
+        Some(n) => {
+            buf.push(1);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn get_opt_u64(buf: &mut &[u8]) -> io::Result<Option<u64>> {
+    match get_u8(buf)? {
+        0 => Ok(None),
+        _ => Ok(Some(get_u64(buf)?)),
+    }
+}
+
+fn put_filter(buf: &mut Vec<u8>, filter: &JobFilter) {
+    match filter.status {
+        Some(status) => buf.extend_from_slice(&[1, status as u8]),
+        None => buf.push(0),
+    }
+    put_opt_str(buf, filter.kind.as_deref());
+    put_opt_u64(buf, filter.created_after.as_ref().map(to_nanos));
+    put_opt_u64(buf, filter.created_before.as_ref().map(to_nanos));
+    put_opt_duration(buf, filter.heartbeat_older_than);
+}
+
+fn get_filter(buf: &mut &[u8]) -> io::Result<JobFilter> {
+    Ok(JobFilter {
+        status: match get_u8(buf)? {
+            0 => None,
+            _ => Some(get_status(buf)?),
+        },
+        kind: get_opt_string(buf)?,
+        created_after: get_opt_u64(buf)?.map(from_nanos),
+        created_before: get_opt_u64(buf)?.map(from_nanos),
+        heartbeat_older_than: get_opt_duration(buf)?,
+    })
+}
+
+fn put_stats(buf: &mut Vec<u8>, stats: &Stats) {
+    for n in [stats.pending, stats.picked, stats.processed, stats.failed] {
+        buf.extend_from_slice(&(n as u64).to_le_bytes());
+    }
+    put_opt_duration(buf, stats.oldest_pending);
+    put_opt_duration(buf, stats.avg_pick);
+    put_opt_duration(buf, stats.avg_completion);
+}
+
+fn get_stats(buf: &mut &[u8]) -> io::Result<Stats> {
+    Ok(Stats {
+        pending: get_u64(buf)? as usize,
+        picked: get_u64(buf)? as usize,
+        processed: get_u64(buf)? as usize,
+        failed: get_u64(buf)? as usize,
+        oldest_pending: get_opt_duration(buf)?,
+        avg_pick: get_opt_duration(buf)?,
+        avg_completion: get_opt_duration(buf)?,
+    })
+}
+
+fn put_error(buf: &mut Vec<u8>, e: &QueueError) {
+    match e {
+        QueueError::NotFound(id) => {
+            buf.push(1);
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        QueueError::DuplicateId(id) => {
+            buf.push(2);
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        QueueError::Poisoned => buf.push(3),
+        QueueError::InvalidTransition { id, from, to } => {
+            buf.push(4);
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.push(*from as u8);
+            buf.push(*to as u8);
+        }
+        QueueError::Full(capacity) => {
+            buf.push(5);
+            buf.extend_from_slice(&(*capacity as u64).to_le_bytes());
+        }
+        QueueError::UnknownQueue(name) => {
+            buf.push(6);
+            put_bytes(buf, name.as_bytes());
+        }
+        QueueError::QueueExists(name) => {
+            buf.push(7);
+            put_bytes(buf, name.as_bytes());
+        }
+        QueueError::IdOutOfRange(id) => {
+            buf.push(9);
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        QueueError::WrongStatus {
+            id,
+            status,
+            expected,
+        } => {
+            buf.push(10);
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.push(*status as u8);
+            buf.push(*expected as u8);
+        }
+        QueueError::Backend(e) => {
+            buf.push(8);
+            put_bytes(buf, e.to_string().as_bytes());
+        }
+    }
+}
+
+fn get_error(buf: &mut &[u8]) -> io::Result<QueueError> {
+    Ok(match get_u8(buf)? {
+        1 => QueueError::NotFound(get_u64(buf)?),
+        2 => QueueError::DuplicateId(get_u64(buf)?),
+        3 => QueueError::Poisoned,
+        4 => QueueError::InvalidTransition {
+            id: get_u64(buf)?,
+            from: get_status(buf)?,
+            to: get_status(buf)?,
+        },
+        5 => QueueError::Full(get_u64(buf)? as usize),
+        6 => QueueError::UnknownQueue(get_string(buf)?),
+        7 => QueueError::QueueExists(get_string(buf)?),
+        8 => QueueError::Backend(get_string(buf)?.into()),
+        9 => QueueError::IdOutOfRange(get_u64(buf)?),
+        10 => QueueError::WrongStatus {
+            id: get_u64(buf)?,
+            status: get_status(buf)?,
+            expected: get_status(buf)?,
+        },
+        other => return Err(invalid(&format!("unknown error code {}", other))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InMemQueue;
+    use std::time::SystemTime;
+
+    // Serves `queue` on a free local port for the rest of the test process.
+    fn serve<Q>(queue: Q) -> RemoteQueue
+    where
+        Q: JobQueue + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = Server::new(Arc::new(queue));
+        thread::spawn(move || server.serve(listener));
+        RemoteQueue::connect(&addr).unwrap()
+    }
+
+    crate::conformance_tests!(remote, |config| serve(
+        InMemQueue::new().with_config(config)
+    ));
+
+    #[test]
+    fn remote_test() {
+        let local = InMemQueue::new();
+        let q = serve(local.clone());
+        let id = q.enqueue(Job::anonymous(b"over the wire")).unwrap();
+        assert_eq!(local.get(id).unwrap().unwrap().payload(), b"over the wire");
+
+        // u64::MAX millis on the wire
+        let job = q.claim_wait(Duration::MAX).unwrap().unwrap();
+        assert_eq!(job.get_status(), &JobStatus::PICKED);
+        q.ack(id).unwrap();
+        let stats = q.stats().unwrap();
+        assert_eq!(stats.processed, 1);
+        assert!(stats.avg_completion.is_some());
+
+        assert!(matches!(
+            q.ack(id),
+            Err(QueueError::InvalidTransition {
+                from: JobStatus::PROCESSED,
+                to: JobStatus::PROCESSED,
+                ..
+            })
+        ));
+        assert!(matches!(q.get(id + 1), Ok(None)));
+    }
+
+    #[test]
+    fn query_test() {
+        let local = InMemQueue::new();
+        let q = serve(local.clone());
+        for i in 0..10 {
+            let kind = if i % 2 == 0 { "email" } else { "sms" };
+            q.enqueue(Job::anonymous(b"x").with_kind(kind)).unwrap();
+        }
+        q.claim().unwrap();
+
+        let filter = JobFilter::new()
+            .with_status(JobStatus::PENDING)
+            .with_kind("email")
+            .with_created_before(SystemTime::now() + Duration::from_secs(60));
+        let page = q.query(&filter, None, 2).unwrap();
+        let ids: Vec<JobId> = page.jobs.iter().map(Job::get_id).collect();
+        assert_eq!(ids, [3, 5]);
+        assert_eq!(page.next, Some(5));
+        let page = q.query(&filter, page.next, 2).unwrap();
+        let ids: Vec<JobId> = page.jobs.iter().map(Job::get_id).collect();
+        assert_eq!(ids, [7, 9]);
+        assert_eq!(page.next, None);
+
+        let stale = JobFilter::new().with_heartbeat_older_than(Duration::from_secs(60));
+        assert!(q.query(&stale, None, 10).unwrap().jobs.is_empty());
+    }
+
+    #[test]
+    fn malformed_request_test() {
+        let q = serve(InMemQueue::new());
+        let err = q.call(&[99]).unwrap_err();
+        assert!(err.to_string().contains("unknown opcode 99"), "{}", err);
+        // the connection is still usable
+        assert_eq!(q.len().unwrap(), 0);
+        assert_eq!(q.idle.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::{Duration, SystemTime};
+
+use crate::{Job, JobId, JobStatus};
+
+// Which jobs a query returns, every condition that is set must hold. The
+// creation range is on the job timestamp, `created_after` inclusive and
+// `created_before` exclusive.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JobFilter {
+    pub status: Option<JobStatus>,
+    pub kind: Option<String>,
+    pub created_after: Option<SystemTime>,
+    pub created_before: Option<SystemTime>,
+    // Only jobs whose last heartbeat is older than this.
+    pub heartbeat_older_than: Option<Duration>,
+}
+
+// One page of a query, in id order. `next` is the cursor to pass as `after`
+// to get the following page, None on the last one. Since ids never change,
+// paging stays stable while jobs are added or removed.
+#[derive(Clone, Debug, Default)]
+pub struct Page {
+    pub jobs: Vec<Job>,
+    pub next: Option<JobId>,
+}
+
+impl JobFilter {
+    pub fn new() -> Self {
+        JobFilter::default()
+    }
+
+    pub fn with_status(mut self, status: JobStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn with_kind(mut self, kind: &str) -> Self {
+        self.kind = Some(kind.to_string());
+        self
+    }
+
+    pub fn with_created_after(mut self, at: SystemTime) -> Self {
+        self.created_after = Some(at);
+        self
+    }
+
+    pub fn with_created_before(mut self, at: SystemTime) -> Self {
+        self.created_before = Some(at);
+        self
+    }
+
+    pub fn with_heartbeat_older_than(mut self, age: Duration) -> Self {
+        self.heartbeat_older_than = Some(age);
+        self
+    }
+
+    pub fn matches(&self, job: &Job, now: SystemTime) -> bool {
+        self.status.is_none_or(|status| job.status == status)
+            && self.kind.as_ref().is_none_or(|kind| &job.kind == kind)
+            && self.created_after.is_none_or(|at| job.timestamp >= at)
+            && self.created_before.is_none_or(|at| job.timestamp < at)
+            && self
+                .heartbeat_older_than
+                .is_none_or(|age| now.duration_since(job.heartbeat).unwrap_or_default() > age)
+    }
+}
+
+impl Page {
+    // Turns up to `limit + 1` jobs in id order, all after `after`, into a
+    // page of `limit`, the extra one only telling whether there's more. An
+    // empty page with more to come points back at where it started, from
+    // 0 if that's the beginning since no job has that id.
+    pub(crate) fn from_jobs(mut jobs: Vec<Job>, after: Option<JobId>, limit: usize) -> Self {
+        let next = if jobs.len() > limit {
+            jobs.truncate(limit);
+            Some(jobs.last().map_or(after.unwrap_or(0), Job::get_id))
+        } else {
+            None
+        };
+        Page { jobs, next }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_test() {
+        let now = SystemTime::now();
+        let job = Job::new(1, b"x").with_kind("email");
+        assert!(JobFilter::new().matches(&job, now));
+        assert!(JobFilter::new()
+            .with_status(JobStatus::PENDING)
+            .with_kind("email")
+            .with_created_after(*job.timestamp())
+            .matches(&job, now));
+        assert!(!JobFilter::new().with_kind("sms").matches(&job, now));
+        assert!(!JobFilter::new()
+            .with_created_before(*job.timestamp())
+            .matches(&job, now));
+
+        let filter = JobFilter::new().with_heartbeat_older_than(Duration::from_secs(60));
+        assert!(!filter.matches(&job, now));
+        assert!(filter.matches(&job, now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn page_test() {
+        let jobs = |n| (1..=n).map(|id| Job::new(id, b"")).collect::<Vec<_>>();
+        let page = Page::from_jobs(jobs(3), None, 2);
+        assert_eq!(page.jobs.len(), 2);
+        assert_eq!(page.next, Some(2));
+        assert_eq!(Page::from_jobs(jobs(2), None, 2).next, None);
+
+        // nothing fits, but there's more
+        let page = Page::from_jobs(jobs(1), None, 0);
+        assert!(page.jobs.is_empty());
+        assert_eq!(page.next, Some(0));
+        assert_eq!(Page::from_jobs(jobs(1), Some(7), 0).next, Some(7));
+        assert_eq!(Page::from_jobs(Vec::new(), Some(7), 0).next, None);
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::JobQueue;
+
+// Background thread calling `JobQueue::reap` every `interval`, until it's
+// stopped or dropped.
+pub struct Reaper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    pub fn spawn<Q>(queue: Arc<Q>, interval: Duration) -> Self
+    where
+        Q: JobQueue + Send + Sync + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                // a failing backend shouldn't kill the reaper, the next
+                // round will try again
+                let _ = queue.reap();
+            }
+        });
+        Reaper {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemQueue, Job, JobStatus, QueueConfig};
+
+    #[test]
+    fn reaper_test() {
+        let q = Arc::new(InMemQueue::new().with_config(QueueConfig {
+            lease: Duration::from_millis(10),
+            ..QueueConfig::default()
+        }));
+        q.enqueue(Job::new(1, b"abandoned")).unwrap();
+        q.claim().unwrap();
+
+        let reaper = Reaper::spawn(Arc::clone(&q), Duration::from_millis(5));
+        std::thread::sleep(Duration::from_millis(100));
+        reaper.stop();
+
+        assert_eq!(q.get(1).unwrap().unwrap().get_status(), &JobStatus::PENDING);
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use crate::{Handler, Job};
+
+// Routes each job to the handler registered for its kind. A Registry is a
+// Handler itself, so it can be handed to a WorkerPool as is. Jobs of a kind
+// nobody registered fail with an "unknown job kind" reason.
+#[derive(Default)]
+pub struct Registry {
+    handlers: HashMap<String, Box<dyn Handler>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    // Registering a kind twice replaces the previous handler.
+    pub fn register<H: Handler>(mut self, kind: &str, handler: H) -> Self {
+        self.handlers.insert(kind.to_string(), Box::new(handler));
+        self
+    }
+
+    pub fn handles(&self, kind: &str) -> bool {
+        self.handlers.contains_key(kind)
+    }
+
+    pub fn kinds(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(String::as_str)
+    }
+}
+
+impl Handler for Registry {
+    fn handle(&self, job: &Job) -> Result<(), String> {
+        match self.handlers.get(job.kind()) {
+            Some(handler) => handler.handle(job),
+            None => Err(format!("unknown job kind {:?}", job.kind())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemQueue, JobQueue, JobStatus, WorkerConfig, WorkerPool};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn routing_test() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (emails, thumbs) = (Arc::clone(&seen), Arc::clone(&seen));
+        let registry = Registry::new()
+            .register("email", move |job: &Job| {
+                emails.lock().unwrap().push(("email", job.get_id()));
+                Ok(())
+            })
+            .register("thumbnail", move |job: &Job| {
+                thumbs.lock().unwrap().push(("thumbnail", job.get_id()));
+                Ok(())
+            });
+        assert!(registry.handles("email"));
+        assert!(!registry.handles("sms"));
+
+        let q = Arc::new(InMemQueue::new());
+        q.enqueue(Job::new(1, b"to: bob").with_kind("email"))
+            .unwrap();
+        q.enqueue(Job::new(2, b"cat.png").with_kind("thumbnail"))
+            .unwrap();
+        q.enqueue(Job::new(3, b"+39...").with_kind("sms")).unwrap();
+
+        let config = WorkerConfig {
+            concurrency: 1,
+            poll: Duration::from_millis(10),
+            ..WorkerConfig::default()
+        };
+        let pool = WorkerPool::spawn(Arc::clone(&q), config, registry);
+        while q.get(3).unwrap().unwrap().get_status() != &JobStatus::FAILED {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        pool.shutdown();
+
+        assert_eq!(*seen.lock().unwrap(), [("email", 1), ("thumbnail", 2)]);
+        assert_eq!(
+            q.get(1).unwrap().unwrap().get_status(),
+            &JobStatus::PROCESSED
+        );
+        assert_eq!(
+            q.get(3).unwrap().unwrap().last_error(),
+            Some("unknown job kind \"sms\"")
+        );
+    }
+}
@@ -0,0 +1,565 @@
+// A front end speaking the Redis protocol (RESP), so that redis-cli and
+// Redis client libraries can use the queues of a QueueStore as if they were
+// lists:
+//
+//     LPUSH key payload [payload ...]   enqueue a job per payload; replies
+//     RPUSH key payload [payload ...]   with the number of PENDING jobs
+//     RPOP key                          take the next job out of the queue,
+//     BRPOP key [key ...] timeout       replying with its payload, or with
+//                                       [key, payload] for BRPOP; a timeout
+//                                       of 0 waits forever
+//     LLEN key                          the number of PENDING jobs
+//     FOXTAIL.CLAIM key [timeout]       claim a job without removing it,
+//                                       replying with [id, payload]
+//     FOXTAIL.ACK key id
+//     FOXTAIL.FAIL key id reason
+//     FOXTAIL.HEARTBEAT key id
+//     PING [message], ECHO message, COMMAND, QUIT
+//
+// Every key is the queue of that name, which a push creates if needed.
+// Unlike a Redis list, a queue has no ends to pick from: both pushes
+// enqueue, and pops take jobs in the queue's own order, oldest first among
+// equal priorities. So LPUSH then RPOP is first in, first out like in
+// Redis, but so is RPUSH then RPOP, where Redis would be last in, first
+// out.
+//
+// Like in Redis, a popped job is gone for good even if its consumer dies;
+// consumers that need retries use FOXTAIL.CLAIM and ack or fail the job
+// themselves.
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Job, JobId, JobQueue, JobStatus, QueueConfig, QueueError, QueueStore};
+
+// Bulk strings and arrays larger than this are refused rather than
+// allocated.
+const MAX_BULK: usize = 64 << 20;
+const MAX_ARGS: usize = 1 << 20;
+
+// A blocking pop on a single key waits on its queue in steps of this long,
+// one on several keys polls them in turn this often.
+const BLOCK_STEP: Duration = Duration::from_secs(1);
+const POLL_STEP: Duration = Duration::from_millis(50);
+
+// Serves the queues of a store to Redis clients, one thread per connection.
+// Queues created by a push get `config`.
+pub struct RespServer<S> {
+    store: Arc<S>,
+    config: QueueConfig,
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl From<QueueError> for Reply {
+    fn from(e: QueueError) -> Self {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+impl<S> RespServer<S>
+where
+    S: QueueStore + Send + Sync + 'static,
+{
+    pub fn new(store: Arc<S>) -> Self {
+        RespServer {
+            store,
+            config: QueueConfig::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: QueueConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // Accepts connections until the listener fails.
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => continue,
+                Err(e) => return Err(e),
+            };
+            let store = Arc::clone(&self.store);
+            let config = self.config.clone();
+            thread::spawn(move || {
+                let _ = handle(&*store, &config, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle<S: QueueStore>(store: &S, config: &QueueConfig, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            // the stream can't be resynchronized after a protocol error
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                let reply = Reply::Error(format!("ERR Protocol error: {}", e));
+                return write_reply(&mut writer, &reply);
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit {
+            Reply::Simple("OK")
+        } else {
+            execute(store, config, &args).unwrap_or_else(|e| e)
+        };
+        write_reply(&mut writer, &reply)?;
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+// Errors are replies too, so that `?` works on both.
+fn execute<S: QueueStore>(
+    store: &S,
+    config: &QueueConfig,
+    args: &[Vec<u8>],
+) -> Result<Reply, Reply> {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+    let arity_ok = match name.as_str() {
+        "COMMAND" => true,
+        "PING" => args.len() <= 1,
+        "ECHO" | "RPOP" | "LLEN" => args.len() == 1,
+        "FOXTAIL.CLAIM" => (1..=2).contains(&args.len()),
+        "FOXTAIL.ACK" | "FOXTAIL.HEARTBEAT" => args.len() == 2,
+        "LPUSH" | "RPUSH" | "BRPOP" => args.len() >= 2,
+        "FOXTAIL.FAIL" => args.len() >= 3,
+        _ => {
+            return Err(Reply::Error(format!(
+                "ERR unknown command '{}'",
+                name.to_lowercase()
+            )))
+        }
+    };
+    if !arity_ok {
+        return Err(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        )));
+    }
+
+    Ok(match name.as_str() {
+        "PING" => match args.first() {
+            Some(message) => Reply::Bulk(Some(message.clone())),
+            None => Reply::Simple("PONG"),
+        },
+        "ECHO" => Reply::Bulk(Some(args[0].clone())),
+        // redis-cli asks for the command table on startup
+        "COMMAND" => Reply::Array(Some(Vec::new())),
+        "LPUSH" | "RPUSH" => {
+            let queue = open_or_create(store, &key(&args[0]), config)?;
+            for payload in &args[1..] {
+                queue.enqueue(Job::anonymous(payload))?;
+            }
+            Reply::Integer(queue.stats()?.pending as i64)
+        }
+        "LLEN" => match open(store, &key(&args[0]))? {
+            Some(queue) => Reply::Integer(queue.stats()?.pending as i64),
+            None => Reply::Integer(0),
+        },
+        "RPOP" => {
+            let keys = [key(&args[0])];
+            Reply::Bulk(pop(store, &keys, None)?.map(|(_, job)| job.payload))
+        }
+        "BRPOP" => {
+            let timeout = parse_timeout(&args[args.len() - 1])?;
+            let keys: Vec<String> = args[..args.len() - 1].iter().map(|k| key(k)).collect();
+            Reply::Array(pop(store, &keys, Some(timeout))?.map(|(key, job)| {
+                vec![
+                    Reply::Bulk(Some(key.into_bytes())),
+                    Reply::Bulk(Some(job.payload)),
+                ]
+            }))
+        }
+        "FOXTAIL.CLAIM" => {
+            let timeout = args.get(1).map(|arg| parse_timeout(arg)).transpose()?;
+            let keys = [key(&args[0])];
+            Reply::Array(claim(store, &keys, timeout)?.map(|(_, _, job)| {
+                vec![
+                    Reply::Integer(job.id as i64),
+                    Reply::Bulk(Some(job.payload)),
+                ]
+            }))
+        }
+        "FOXTAIL.ACK" => {
+            store.queue(&key(&args[0]))?.ack(parse_id(&args[1])?)?;
+            Reply::Simple("OK")
+        }
+        "FOXTAIL.HEARTBEAT" => {
+            store
+                .queue(&key(&args[0]))?
+                .heartbeat(parse_id(&args[1])?)?;
+            Reply::Simple("OK")
+        }
+        _ => {
+            let reason = args[2..]
+                .iter()
+                .map(|arg| String::from_utf8_lossy(arg))
+                .collect::<Vec<_>>()
+                .join(" ");
+            store
+                .queue(&key(&args[0]))?
+                .fail(parse_id(&args[1])?, &reason)?;
+            Reply::Simple("OK")
+        }
+    })
+}
+
+fn key(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+// None for a key nothing was pushed to yet, an empty list to Redis.
+fn open<S: QueueStore>(store: &S, name: &str) -> Result<Option<S::Queue>, QueueError> {
+    match store.queue(name) {
+        Ok(queue) => Ok(Some(queue)),
+        Err(QueueError::UnknownQueue(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn open_or_create<S: QueueStore>(
+    store: &S,
+    name: &str,
+    config: &QueueConfig,
+) -> Result<S::Queue, QueueError> {
+    if let Some(queue) = open(store, name)? {
+        return Ok(queue);
+    }
+    match store.create_queue(name, config.clone()) {
+        // another connection just created it
+        Err(QueueError::QueueExists(_)) => store.queue(name),
+        res => res,
+    }
+}
+
+// Claims a job from the first of `keys` that has one. Waits up to `timeout`
+// if given, and for as long as it takes when it's zero, like in Redis, or
+// too long to have a deadline.
+fn claim<S: QueueStore>(
+    store: &S,
+    keys: &[String],
+    timeout: Option<Duration>,
+) -> Result<Option<(String, S::Queue, Job)>, QueueError> {
+    let deadline = timeout
+        .filter(|timeout| !timeout.is_zero())
+        .and_then(|timeout| Instant::now().checked_add(timeout));
+    let step = if keys.len() == 1 {
+        BLOCK_STEP
+    } else {
+        POLL_STEP
+    };
+    // the first round only looks
+    let mut wait = Duration::ZERO;
+    loop {
+        for key in keys {
+            let wait = deadline.map_or(wait, |deadline| {
+                wait.min(deadline.saturating_duration_since(Instant::now()))
+            });
+            match open(store, key)? {
+                Some(queue) => {
+                    if let Some(job) = queue.claim_wait(wait)? {
+                        return Ok(Some((key.clone(), queue, job)));
+                    }
+                }
+                None => thread::sleep(wait),
+            }
+        }
+        let expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if timeout.is_none() || expired {
+            return Ok(None);
+        }
+        wait = step;
+    }
+}
+
+// Claims a job and takes it out of its queue. Until it's out it's PICKED,
+// so if we die in between, the job's lease runs out and it's handed out
+// again rather than lost.
+fn pop<S: QueueStore>(
+    store: &S,
+    keys: &[String],
+    timeout: Option<Duration>,
+) -> Result<Option<(String, Job)>, QueueError> {
+    let Some((key, queue, job)) = claim(store, keys, timeout)? else {
+        return Ok(None);
+    };
+    let job = queue.dequeue_if(job.id, JobStatus::PICKED)?;
+    Ok(Some((key, job)))
+}
+
+fn parse_timeout(arg: &[u8]) -> Result<Duration, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| Reply::Error("ERR timeout is not a float or out of range".to_string()))
+}
+
+fn parse_id(arg: &[u8]) -> Result<JobId, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Reply::Error("ERR value is not an integer or out of range".to_string()))
+}
+
+fn invalid(what: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, what.into())
+}
+
+// A line without its \r\n, None at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let n = reader
+        .by_ref()
+        .take(MAX_BULK as u64)
+        .read_until(b'\n', &mut line)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("line too long or unterminated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(line: &[u8], max: usize) -> io::Result<usize> {
+    let len: i64 = std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid length"))?;
+    usize::try_from(len)
+        .ok()
+        .filter(|&len| len <= max)
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+// Either an array of bulk strings, as clients send, or an inline command
+// split on whitespace, as typed into telnet. None at the end of the stream.
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    };
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| invalid("truncated command"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| invalid(format!("expected '$', got {:?}", line.first())))?;
+        let len = parse_len(len, MAX_BULK)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string not terminated by \\r\\n"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn encode_reply(buf: &mut Vec<u8>, reply: &Reply) {
+    match reply {
+        Reply::Simple(s) => buf.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+        // error messages must stay on one line
+        Reply::Error(e) => {
+            buf.push(b'-');
+            buf.extend(
+                e.bytes()
+                    .map(|b| if b == b'\r' || b == b'\n' { b' ' } else { b }),
+            );
+            buf.extend_from_slice(b"\r\n");
+        }
+        Reply::Integer(n) => buf.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+        Reply::Bulk(None) => buf.extend_from_slice(b"$-1\r\n"),
+        Reply::Bulk(Some(bytes)) => {
+            buf.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+            buf.extend_from_slice(bytes);
+            buf.extend_from_slice(b"\r\n");
+        }
+        Reply::Array(None) => buf.extend_from_slice(b"*-1\r\n"),
+        Reply::Array(Some(items)) => {
+            buf.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+            for item in items {
+                encode_reply(buf, item);
+            }
+        }
+    }
+}
+
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> io::Result<()> {
+    let mut buf = Vec::new();
+    encode_reply(&mut buf, reply);
+    writer.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InMemStore;
+
+    #[test]
+    fn read_command_test() {
+        let mut input =
+            &b"*3\r\n$5\r\nLPUSH\r\n$4\r\njobs\r\n$6\r\na\r\nb\0c\r\nPING  hello\r\n"[..];
+        let args = read_command(&mut input).unwrap().unwrap();
+        assert_eq!(args, [&b"LPUSH"[..], b"jobs", b"a\r\nb\0c"]);
+        let args = read_command(&mut input).unwrap().unwrap();
+        assert_eq!(args, [&b"PING"[..], b"hello"]);
+        assert!(read_command(&mut input).unwrap().is_none());
+
+        let mut bad = &b"*1\r\n$3\r\nfoobar\r\n"[..];
+        assert!(read_command(&mut bad).is_err());
+    }
+
+    // Sends a command the way client libraries do and checks the reply.
+    fn check(conn: &mut TcpStream, args: &[&str], expected: &str) {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        conn.write_all(buf.as_bytes()).unwrap();
+        let mut reply = vec![0; expected.len()];
+        conn.read_exact(&mut reply).unwrap();
+        assert_eq!(String::from_utf8_lossy(&reply), expected, "{:?}", args);
+    }
+
+    fn serve(store: &Arc<InMemStore>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = RespServer::new(Arc::clone(store));
+        thread::spawn(move || server.serve(listener));
+        TcpStream::connect(addr).unwrap()
+    }
+
+    #[test]
+    fn resp_test() {
+        let store = Arc::new(InMemStore::new());
+        let mut conn = serve(&store);
+
+        check(&mut conn, &["PING"], "+PONG\r\n");
+        check(&mut conn, &["LPUSH", "mail", "hello", "world"], ":2\r\n");
+        check(&mut conn, &["RPUSH", "mail", "!"], ":3\r\n");
+        check(&mut conn, &["llen", "mail"], ":3\r\n");
+        let q = store.queue("mail").unwrap();
+
+        check(
+            &mut conn,
+            &["BRPOP", "mail", "1"],
+            "*2\r\n$4\r\nmail\r\n$5\r\nhello\r\n",
+        );
+        assert!(q.get(1).unwrap().is_none());
+        assert_eq!(q.len().unwrap(), 2);
+
+        check(
+            &mut conn,
+            &["FOXTAIL.CLAIM", "mail"],
+            "*2\r\n:2\r\n$5\r\nworld\r\n",
+        );
+        check(&mut conn, &["FOXTAIL.HEARTBEAT", "mail", "2"], "+OK\r\n");
+        check(
+            &mut conn,
+            &["FOXTAIL.FAIL", "mail", "2", "no", "luck"],
+            "+OK\r\n",
+        );
+        assert_eq!(q.get(2).unwrap().unwrap().last_error(), Some("no luck"));
+        check(
+            &mut conn,
+            &["FOXTAIL.ACK", "mail", "2"],
+            "-ERR Job with ID 2 can't go from FAILED to PROCESSED\r\n",
+        );
+        check(
+            &mut conn,
+            &["FOXTAIL.ACK", "nope", "2"],
+            "-ERR Queue \"nope\" not found\r\n",
+        );
+
+        // RPUSHed last but still popped last, not first as in Redis
+        check(&mut conn, &["RPOP", "mail"], "$1\r\n!\r\n");
+        check(&mut conn, &["RPOP", "mail"], "$-1\r\n");
+        assert_eq!(q.len().unwrap(), 1); // the failed one
+        check(&mut conn, &["BRPOP", "mail", "0.05"], "*-1\r\n");
+        check(
+            &mut conn,
+            &["LPUSH", "mail"],
+            "-ERR wrong number of arguments for 'lpush' command\r\n",
+        );
+        check(
+            &mut conn,
+            &["FLUSHALL"],
+            "-ERR unknown command 'flushall'\r\n",
+        );
+
+        conn.write_all(b"QUIT\r\n").unwrap();
+        let mut rest = String::new();
+        conn.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "+OK\r\n");
+    }
+
+    #[test]
+    fn keys_test() {
+        let store = Arc::new(InMemStore::new());
+        let mut conn = serve(&store);
+
+        check(&mut conn, &["RPUSH", "email", "a", "b"], ":2\r\n");
+        check(&mut conn, &["RPUSH", "sms", "c"], ":1\r\n");
+        check(&mut conn, &["LLEN", "push"], ":0\r\n");
+        check(&mut conn, &["RPOP", "push"], "$-1\r\n");
+
+        check(&mut conn, &["RPOP", "email"], "$1\r\na\r\n");
+        check(
+            &mut conn,
+            &["BRPOP", "push", "email", "1"],
+            "*2\r\n$5\r\nemail\r\n$1\r\nb\r\n",
+        );
+        check(&mut conn, &["RPOP", "email"], "$-1\r\n");
+        check(&mut conn, &["LLEN", "email"], ":0\r\n");
+        check(&mut conn, &["LLEN", "sms"], ":1\r\n");
+        check(&mut conn, &["RPUSH", "sms", "d"], ":2\r\n");
+        check(
+            &mut conn,
+            &["BRPOP", "sms", "1e19"],
+            "*2\r\n$3\r\nsms\r\n$1\r\nc\r\n",
+        );
+        check(&mut conn, &["LLEN", "sms"], ":1\r\n");
+        assert!(store.queue("email").unwrap().is_empty().unwrap());
+        let sms = store.queue("sms").unwrap().list().unwrap();
+        assert_eq!(sms.len(), 1);
+        assert_eq!(sms[0].get_status(), &JobStatus::PENDING);
+        assert_eq!(store.queues().unwrap(), ["email", "sms"]);
+    }
+}
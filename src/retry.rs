@@ -0,0 +1,179 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::time::Duration;
+
+// How long a failed job waits before it becomes PENDING again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Backoff {
+    Fixed(Duration),
+    // base, 2 * base, 4 * base, ... never more than `max`
+    Exponential { base: Duration, max: Duration },
+    // like Exponential, but picks a random delay between zero and that value
+    // so that jobs failing together don't all come back at once
+    Jittered { base: Duration, max: Duration },
+}
+
+// A job is tried at most `max_attempts` times; every failure before the
+// last one puts it back to PENDING after the backoff delay. The default
+// never retries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Backoff::Fixed(Duration::ZERO),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, backoff: Backoff) -> Self {
+        RetryPolicy {
+            max_attempts,
+            backoff,
+        }
+    }
+
+    // Delay before the attempt following the `attempt`-th one (1-based).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(d) => d,
+            Backoff::Exponential { base, max } => exponential(base, max, attempt),
+            Backoff::Jittered { base, max } => {
+                exponential(base, max, attempt).mul_f64(random_fraction())
+            }
+        }
+    }
+
+    pub fn should_retry(&self, attempts: u32) -> bool {
+        attempts < self.max_attempts
+    }
+}
+
+fn exponential(base: Duration, max: Duration, attempt: u32) -> Duration {
+    let factor = 1u32 << attempt.saturating_sub(1).min(31);
+    base.checked_mul(factor).unwrap_or(max).min(max)
+}
+
+// A number in [0, 1), good enough to spread retries around.
+fn random_fraction() -> f64 {
+    let n = RandomState::new().build_hasher().finish();
+    (n >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Policies are written as `<max_attempts>:fixed:<ms>`,
+// `<max_attempts>:exp:<base_ms>:<max_ms>` or
+// `<max_attempts>:jitter:<base_ms>:<max_ms>`, which is also how the
+// persistent backends store per-job overrides.
+impl fmt::Display for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.backoff {
+            Backoff::Fixed(d) => write!(f, "{}:fixed:{}", self.max_attempts, d.as_millis()),
+            Backoff::Exponential { base, max } => write!(
+                f,
+                "{}:exp:{}:{}",
+                self.max_attempts,
+                base.as_millis(),
+                max.as_millis()
+            ),
+            Backoff::Jittered { base, max } => write!(
+                f,
+                "{}:jitter:{}:{}",
+                self.max_attempts,
+                base.as_millis(),
+                max.as_millis()
+            ),
+        }
+    }
+}
+
+impl FromStr for RetryPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let num = |i: usize| -> Result<u64, String> {
+            parts
+                .get(i)
+                .ok_or_else(|| format!("missing field in retry policy {:?}", s))?
+                .parse::<u64>()
+                .map_err(|e| format!("bad retry policy {:?}: {}", s, e))
+        };
+        let max_attempts = num(0)? as u32;
+        let backoff = match parts.get(1).copied() {
+            Some("fixed") if parts.len() == 3 => Backoff::Fixed(Duration::from_millis(num(2)?)),
+            Some("exp") if parts.len() == 4 => Backoff::Exponential {
+                base: Duration::from_millis(num(2)?),
+                max: Duration::from_millis(num(3)?),
+            },
+            Some("jitter") if parts.len() == 4 => Backoff::Jittered {
+                base: Duration::from_millis(num(2)?),
+                max: Duration::from_millis(num(3)?),
+            },
+            _ => return Err(format!("bad retry policy {:?}", s)),
+        };
+        Ok(RetryPolicy::new(max_attempts, backoff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_test() {
+        let ms = Duration::from_millis;
+        let fixed = RetryPolicy::new(3, Backoff::Fixed(ms(100)));
+        assert_eq!(fixed.delay(1), ms(100));
+        assert_eq!(fixed.delay(5), ms(100));
+
+        let exp = RetryPolicy::new(
+            10,
+            Backoff::Exponential {
+                base: ms(100),
+                max: ms(1000),
+            },
+        );
+        assert_eq!(exp.delay(1), ms(100));
+        assert_eq!(exp.delay(2), ms(200));
+        assert_eq!(exp.delay(4), ms(800));
+        assert_eq!(exp.delay(5), ms(1000));
+        assert_eq!(exp.delay(100), ms(1000));
+
+        let jitter = RetryPolicy::new(
+            10,
+            Backoff::Jittered {
+                base: ms(100),
+                max: ms(1000),
+            },
+        );
+        for attempt in 1..10 {
+            assert!(jitter.delay(attempt) <= exp.delay(attempt));
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        for policy in [
+            RetryPolicy::default(),
+            RetryPolicy::new(
+                5,
+                Backoff::Jittered {
+                    base: Duration::from_millis(250),
+                    max: Duration::from_secs(60),
+                },
+            ),
+        ] {
+            assert_eq!(policy.to_string().parse::<RetryPolicy>(), Ok(policy));
+        }
+        assert!("3:sometimes:5".parse::<RetryPolicy>().is_err());
+        assert!("3:fixed".parse::<RetryPolicy>().is_err());
+    }
+}
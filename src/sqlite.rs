@@ -0,0 +1,1124 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::Value;
+use rusqlite::{
+    params, params_from_iter, Connection, OptionalExtension, Row, Transaction, TransactionBehavior,
+};
+
+use crate::id::check_id;
+use crate::stats::Timing;
+use crate::{
+    IdStrategy, Job, JobFilter, JobId, JobQueue, JobStatus, Page, QueueConfig, QueueError,
+    QueueStore, RetryPolicy, Stats, MAX_JOB_ID,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS queues (
+        name         TEXT    PRIMARY KEY,
+        last_id      INTEGER NOT NULL,
+        lease        INTEGER NOT NULL,
+        max_expiries INTEGER NOT NULL,
+        capacity     INTEGER,
+        retry        TEXT    NOT NULL,
+        ids          TEXT    NOT NULL,
+        picks        INTEGER NOT NULL DEFAULT 0,
+        pick_micros  INTEGER NOT NULL DEFAULT 0,
+        acks         INTEGER NOT NULL DEFAULT 0,
+        ack_micros   INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS jobs (
+        seq       INTEGER PRIMARY KEY AUTOINCREMENT,
+        queue     TEXT    NOT NULL,
+        id        INTEGER NOT NULL,
+        status    TEXT    NOT NULL,
+        payload   BLOB    NOT NULL,
+        timestamp INTEGER NOT NULL,
+        heartbeat INTEGER NOT NULL,
+        errors    BLOB    NOT NULL,
+        expiries  INTEGER NOT NULL,
+        attempts  INTEGER NOT NULL,
+        retry     TEXT,
+        run_at    INTEGER,
+        priority  INTEGER NOT NULL,
+        kind      TEXT    NOT NULL,
+        codec     TEXT
+    );
+";
+
+const INDEXES: &str = "
+    CREATE UNIQUE INDEX IF NOT EXISTS jobs_queue_id ON jobs (queue, id);
+    CREATE INDEX IF NOT EXISTS jobs_queue_status ON jobs (queue, status, priority DESC, seq);
+    CREATE INDEX IF NOT EXISTS jobs_queue_status_id ON jobs (queue, status, id);
+";
+
+// The layout of SCHEMA and INDEXES, kept in `PRAGMA user_version`. Changing
+// either means bumping it along with a migration from the previous one.
+const SCHEMA_VERSION: i64 = 1;
+
+// The queue used by SqliteQueue::open and JobQueue::new.
+const DEFAULT_QUEUE: &str = "default";
+
+// Named queues sharing one database. Every queue has a row in `queues`,
+// holding its config, the highest job id handed out so far, so that ids
+// aren't reused after the job holding the highest one is dequeued, and how
+// many picks and acks there were and how long they took, for stats.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+// A handle to one queue of a SqliteStore. Jobs of all queues are kept in a
+// single table, where `seq` preserves insertion order.
+pub struct SqliteQueue {
+    conn: Arc<Mutex<Connection>>,
+    queue: String,
+    config: QueueConfig,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, QueueError> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    // A store that lives and dies with the process.
+    pub fn open_in_memory() -> Result<Self, QueueError> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<Self, QueueError> {
+        conn.busy_timeout(Duration::from_secs(5))?;
+        // immediate, so that two processes creating the same database don't
+        // mistake each other's tables for an unknown layout
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version: i64 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let has_tables: bool =
+            tx.query_row("SELECT COUNT(*) > 0 FROM sqlite_master", [], |row| {
+                row.get(0)
+            })?;
+        match version {
+            SCHEMA_VERSION => {}
+            0 if !has_tables => {
+                tx.execute_batch(SCHEMA)?;
+                tx.execute_batch(INDEXES)?;
+                tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            }
+            _ => {
+                let msg = format!(
+                    "unsupported database schema version {}, expected {}",
+                    version, SCHEMA_VERSION
+                );
+                return Err(QueueError::Backend(msg.into()));
+            }
+        }
+        tx.commit()?;
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Creates the queue with `config` unless it exists already, in which
+    // case its stored config wins.
+    fn ensure_queue(&self, name: &str, config: QueueConfig) -> Result<SqliteQueue, QueueError> {
+        match self.create_queue(name, config) {
+            Err(QueueError::QueueExists(_)) => self.queue(name),
+            res => res,
+        }
+    }
+
+    fn handle(&self, name: &str, config: QueueConfig) -> SqliteQueue {
+        SqliteQueue {
+            conn: Arc::clone(&self.conn),
+            queue: name.to_string(),
+            config,
+        }
+    }
+}
+
+impl QueueStore for SqliteStore {
+    type Queue = SqliteQueue;
+
+    fn create_queue(&self, name: &str, config: QueueConfig) -> Result<SqliteQueue, QueueError> {
+        let conn = self.conn.lock()?;
+        let created = conn.execute(
+            "INSERT OR IGNORE INTO queues (name, last_id, lease, max_expiries, capacity, retry, ids)
+             VALUES (?1, 0, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                name,
+                config.lease.as_nanos() as i64,
+                config.max_expiries,
+                config.capacity.map(|c| c as i64),
+                config.retry.to_string(),
+                ids_to_str(config.ids),
+            ],
+        )?;
+        if created == 0 {
+            return Err(QueueError::QueueExists(name.to_string()));
+        }
+        Ok(self.handle(name, config))
+    }
+
+    fn queue(&self, name: &str) -> Result<SqliteQueue, QueueError> {
+        let config = self
+            .conn
+            .lock()?
+            .query_row(
+                "SELECT * FROM queues WHERE name = ?1",
+                params![name],
+                config_from_row,
+            )
+            .optional()?
+            .ok_or_else(|| QueueError::UnknownQueue(name.to_string()))?;
+        Ok(self.handle(name, config))
+    }
+
+    fn queues(&self) -> Result<Vec<String>, QueueError> {
+        let conn = self.conn.lock()?;
+        let mut stmt = conn.prepare("SELECT name FROM queues ORDER BY name")?;
+        let names = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(names)
+    }
+
+    fn delete_queue(&self, name: &str) -> Result<(), QueueError> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if tx.execute("DELETE FROM queues WHERE name = ?1", params![name])? == 0 {
+            return Err(QueueError::UnknownQueue(name.to_string()));
+        }
+        tx.execute("DELETE FROM jobs WHERE queue = ?1", params![name])?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl SqliteQueue {
+    // Adds to the pick or ack timings of the queue when `job` was just
+    // picked or acked.
+    fn record_timing(
+        &self,
+        tx: &Transaction,
+        before: JobStatus,
+        job: &Job,
+        now: SystemTime,
+    ) -> rusqlite::Result<()> {
+        let (sql, since) = match (before, job.status) {
+            (JobStatus::PENDING, JobStatus::PICKED) => (
+                "UPDATE queues SET picks = picks + 1, pick_micros = pick_micros + ?1
+                 WHERE name = ?2",
+                job.heartbeat.duration_since(job.timestamp),
+            ),
+            (JobStatus::PICKED, JobStatus::PROCESSED) => (
+                "UPDATE queues SET acks = acks + 1, ack_micros = ack_micros + ?1
+                 WHERE name = ?2",
+                now.duration_since(job.timestamp),
+            ),
+            _ => return Ok(()),
+        };
+        let micros = since.unwrap_or_default().as_micros() as i64;
+        tx.execute(sql, params![micros, self.queue])?;
+        Ok(())
+    }
+
+    // The default queue of the database at `path`, created if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, QueueError> {
+        SqliteStore::open(path)?.ensure_queue(DEFAULT_QUEUE, QueueConfig::default())
+    }
+
+    // Only affects this handle, the config stored for the queue is the one
+    // it was created with. The clock is never stored, handles from
+    // SqliteStore::queue use the system clock.
+    pub fn with_config(mut self, config: QueueConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    pub fn name(&self) -> &str {
+        &self.queue
+    }
+
+    // Loads the job inside an immediate transaction, so no other connection
+    // can touch it until `f` has run and the result is written back.
+    fn update<F>(&self, id_job: JobId, f: F) -> Result<(), QueueError>
+    where
+        F: FnOnce(&mut Job, SystemTime) -> Result<(), QueueError>,
+    {
+        if id_job > MAX_JOB_ID {
+            return Err(QueueError::NotFound(id_job));
+        }
+        let now = self.config.clock.now();
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let found = tx
+            .query_row(
+                "SELECT seq, * FROM jobs WHERE queue = ?1 AND id = ?2",
+                params![self.queue, id_job],
+                |row| Ok((row.get::<_, i64>("seq")?, job_from_row(row)?)),
+            )
+            .optional()?;
+        let (seq, mut job) = found.ok_or(QueueError::NotFound(id_job))?;
+        let before = job.status;
+        f(&mut job, now)?;
+        save_job(&tx, seq, &job)?;
+        self.record_timing(&tx, before, &job, now)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn to_nanos(t: &SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+fn from_nanos(n: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(n as u64)
+}
+
+fn status_to_str(status: &JobStatus) -> &'static str {
+    match status {
+        JobStatus::PENDING => "PENDING",
+        JobStatus::PICKED => "PICKED",
+        JobStatus::PROCESSED => "PROCESSED",
+        JobStatus::FAILED => "FAILED",
+    }
+}
+
+fn status_from_str(s: &str) -> rusqlite::Result<JobStatus> {
+    match s {
+        "PENDING" => Ok(JobStatus::PENDING),
+        "PICKED" => Ok(JobStatus::PICKED),
+        "PROCESSED" => Ok(JobStatus::PROCESSED),
+        "FAILED" => Ok(JobStatus::FAILED),
+        other => Err(rusqlite::Error::InvalidColumnType(
+            2,
+            format!("status {}", other),
+            rusqlite::types::Type::Text,
+        )),
+    }
+}
+
+// The failure history is stored as a sequence of length-prefixed strings.
+fn encode_errors(errors: &[String]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for e in errors {
+        buf.extend_from_slice(&(e.len() as u32).to_le_bytes());
+        buf.extend_from_slice(e.as_bytes());
+    }
+    buf
+}
+
+fn decode_errors(mut buf: &[u8]) -> Vec<String> {
+    let mut errors = Vec::new();
+    while buf.len() >= 4 {
+        let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let end = (4 + len).min(buf.len());
+        errors.push(String::from_utf8_lossy(&buf[4..end]).into_owned());
+        buf = &buf[end..];
+    }
+    errors
+}
+
+fn retry_from_str(s: &str) -> rusqlite::Result<RetryPolicy> {
+    s.parse().map_err(|e: String| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+fn ids_to_str(ids: IdStrategy) -> &'static str {
+    match ids {
+        IdStrategy::Sequential => "sequential",
+        IdStrategy::TimeOrdered => "time-ordered",
+    }
+}
+
+fn ids_from_str(s: &str) -> rusqlite::Result<IdStrategy> {
+    match s {
+        "sequential" => Ok(IdStrategy::Sequential),
+        "time-ordered" => Ok(IdStrategy::TimeOrdered),
+        other => Err(rusqlite::Error::FromSqlConversionFailure(
+            0,
+            rusqlite::types::Type::Text,
+            format!("id strategy {}", other).into(),
+        )),
+    }
+}
+
+fn config_from_row(row: &Row) -> rusqlite::Result<QueueConfig> {
+    Ok(QueueConfig {
+        lease: Duration::from_nanos(row.get::<_, i64>("lease")? as u64),
+        max_expiries: row.get("max_expiries")?,
+        capacity: row.get::<_, Option<i64>>("capacity")?.map(|c| c as usize),
+        retry: retry_from_str(&row.get::<_, String>("retry")?)?,
+        ids: ids_from_str(&row.get::<_, String>("ids")?)?,
+        ..QueueConfig::default()
+    })
+}
+
+fn job_from_row(row: &Row) -> rusqlite::Result<Job> {
+    let status: String = row.get("status")?;
+    let retry: Option<String> = row.get("retry")?;
+    Ok(Job {
+        id: row.get("id")?,
+        status: status_from_str(&status)?,
+        payload: row.get("payload")?,
+        timestamp: from_nanos(row.get("timestamp")?),
+        heartbeat: from_nanos(row.get("heartbeat")?),
+        errors: decode_errors(&row.get::<_, Vec<u8>>("errors")?),
+        expiries: row.get("expiries")?,
+        attempts: row.get("attempts")?,
+        retry: retry.as_deref().map(retry_from_str).transpose()?,
+        run_at: row.get::<_, Option<i64>>("run_at")?.map(from_nanos),
+        priority: row.get("priority")?,
+        kind: row.get("kind")?,
+        codec: row.get("codec")?,
+        stamped: true,
+        delay: None,
+    })
+}
+
+fn save_job(tx: &Transaction, seq: i64, job: &Job) -> rusqlite::Result<()> {
+    tx.execute(
+        "UPDATE jobs SET status = ?1, heartbeat = ?2, errors = ?3, expiries = ?4,
+                         attempts = ?5, run_at = ?6
+         WHERE seq = ?7",
+        params![
+            status_to_str(&job.status),
+            to_nanos(&job.heartbeat),
+            encode_errors(&job.errors),
+            job.expiries,
+            job.attempts,
+            job.run_at.as_ref().map(to_nanos),
+            seq,
+        ],
+    )?;
+    Ok(())
+}
+
+impl JobQueue for SqliteQueue {
+    // A queue created through the trait lives in a private in-memory
+    // database; use `SqliteQueue::open` to get persistence.
+    fn new() -> Self {
+        SqliteStore::open_in_memory()
+            .and_then(|store| store.ensure_queue(DEFAULT_QUEUE, QueueConfig::default()))
+            .expect("Failed to create sqlite database")
+    }
+
+    fn enqueue(&self, mut j: Job) -> Result<JobId, QueueError> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if let Some(capacity) = self.config.capacity {
+            let count: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM jobs WHERE queue = ?1",
+                params![self.queue],
+                |row| row.get(0),
+            )?;
+            if count as usize >= capacity {
+                return Err(QueueError::Full(capacity));
+            }
+        }
+        let last: JobId = tx
+            .query_row(
+                "SELECT last_id FROM queues WHERE name = ?1",
+                params![self.queue],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| QueueError::UnknownQueue(self.queue.clone()))?;
+        let now = self.config.clock.now();
+        j.stamp(now);
+        if j.id == 0 {
+            j.id = self.config.ids.next(last, now)?;
+        } else {
+            check_id(j.id)?;
+            let taken = tx
+                .query_row(
+                    "SELECT 1 FROM jobs WHERE queue = ?1 AND id = ?2",
+                    params![self.queue, j.id],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if taken.is_some() {
+                return Err(QueueError::DuplicateId(j.id));
+            }
+        }
+        tx.execute(
+            "UPDATE queues SET last_id = ?1 WHERE name = ?2",
+            params![last.max(j.id), self.queue],
+        )?;
+        tx.execute(
+            "INSERT INTO jobs (queue, id, status, payload, timestamp, heartbeat, errors,
+                               expiries, attempts, retry, run_at, priority, kind, codec)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            params![
+                self.queue,
+                j.id,
+                status_to_str(&j.status),
+                j.payload,
+                to_nanos(&j.timestamp),
+                to_nanos(&j.heartbeat),
+                encode_errors(&j.errors),
+                j.expiries,
+                j.attempts,
+                j.retry.as_ref().map(RetryPolicy::to_string),
+                j.run_at.as_ref().map(to_nanos),
+                j.priority,
+                j.kind,
+                j.codec,
+            ],
+        )?;
+        tx.commit()?;
+        Ok(j.id)
+    }
+
+    fn get(&self, id_job: JobId) -> Result<Option<Job>, QueueError> {
+        if id_job > MAX_JOB_ID {
+            return Ok(None);
+        }
+        let conn = self.conn.lock()?;
+        let job = conn
+            .query_row(
+                "SELECT * FROM jobs WHERE queue = ?1 AND id = ?2",
+                params![self.queue, id_job],
+                job_from_row,
+            )
+            .optional()?;
+        Ok(job)
+    }
+
+    fn dequeue(&self, id_job: JobId) -> Result<(), QueueError> {
+        if id_job > MAX_JOB_ID {
+            return Err(QueueError::NotFound(id_job));
+        }
+        let conn = self.conn.lock()?;
+        let removed = conn.execute(
+            "DELETE FROM jobs WHERE queue = ?1 AND id = ?2",
+            params![self.queue, id_job],
+        )?;
+        if removed == 0 {
+            return Err(QueueError::NotFound(id_job));
+        }
+        Ok(())
+    }
+
+    fn dequeue_if(&self, id_job: JobId, status: JobStatus) -> Result<Job, QueueError> {
+        if id_job > MAX_JOB_ID {
+            return Err(QueueError::NotFound(id_job));
+        }
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let job = tx
+            .query_row(
+                "SELECT * FROM jobs WHERE queue = ?1 AND id = ?2",
+                params![self.queue, id_job],
+                job_from_row,
+            )
+            .optional()?
+            .ok_or(QueueError::NotFound(id_job))?;
+        if job.status != status {
+            return Err(QueueError::WrongStatus {
+                id: id_job,
+                status: job.status,
+                expected: status,
+            });
+        }
+        tx.execute(
+            "DELETE FROM jobs WHERE queue = ?1 AND id = ?2",
+            params![self.queue, id_job],
+        )?;
+        tx.commit()?;
+        Ok(job)
+    }
+
+    fn claim(&self) -> Result<Option<Job>, QueueError> {
+        let mut conn = self.conn.lock()?;
+        // The immediate transaction keeps other connections to the same
+        // database file from picking the job between the SELECT and UPDATE.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let now = self.config.clock.now();
+        let found = tx
+            .query_row(
+                "SELECT seq, * FROM jobs
+                 WHERE queue = ?1 AND status = ?2 AND (run_at IS NULL OR run_at <= ?3)
+                 ORDER BY priority DESC, seq LIMIT 1",
+                params![
+                    self.queue,
+                    status_to_str(&JobStatus::PENDING),
+                    to_nanos(&now)
+                ],
+                |row| Ok((row.get::<_, i64>("seq")?, job_from_row(row)?)),
+            )
+            .optional()?;
+        let Some((seq, mut job)) = found else {
+            return Ok(None);
+        };
+        job.pick(now)?;
+        save_job(&tx, seq, &job)?;
+        self.record_timing(&tx, JobStatus::PENDING, &job, now)?;
+        tx.commit()?;
+        Ok(Some(job))
+    }
+
+    fn ack(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, _| job.ack())
+    }
+
+    fn fail(&self, id_job: JobId, reason: &str) -> Result<(), QueueError> {
+        self.update(id_job, |job, now| job.fail(reason, &self.config.retry, now))
+    }
+
+    fn retry(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, _| job.revive())
+    }
+
+    fn heartbeat(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, now| job.touch(now))
+    }
+
+    fn reap(&self) -> Result<usize, QueueError> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let now = self.config.clock.now();
+        let deadline = now.checked_sub(self.config.lease).unwrap_or(UNIX_EPOCH);
+        let expired = {
+            let mut stmt = tx.prepare(
+                "SELECT seq, * FROM jobs WHERE queue = ?1 AND status = ?2 AND heartbeat < ?3",
+            )?;
+            let rows = stmt.query_map(
+                params![
+                    self.queue,
+                    status_to_str(&JobStatus::PICKED),
+                    to_nanos(&deadline)
+                ],
+                |row| Ok((row.get::<_, i64>("seq")?, job_from_row(row)?)),
+            )?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        for (seq, mut job) in expired.iter().cloned() {
+            job.expire(self.config.max_expiries)?;
+            save_job(&tx, seq, &job)?;
+        }
+        tx.commit()?;
+        Ok(expired.len())
+    }
+
+    fn len(&self) -> Result<usize, QueueError> {
+        let conn = self.conn.lock()?;
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM jobs WHERE queue = ?1",
+            params![self.queue],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn list(&self) -> Result<Vec<Job>, QueueError> {
+        let conn = self.conn.lock()?;
+        let mut stmt = conn.prepare("SELECT * FROM jobs WHERE queue = ?1 ORDER BY seq")?;
+        let jobs = stmt
+            .query_map(params![self.queue], job_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(jobs)
+    }
+
+    fn stats(&self) -> Result<Stats, QueueError> {
+        let conn = self.conn.lock()?;
+        let mut stats = conn.query_row(
+            "SELECT picks, pick_micros, acks, ack_micros FROM queues WHERE name = ?1",
+            params![self.queue],
+            |row| {
+                let picks = Timing::from_micros(row.get(0)?, row.get(1)?);
+                let acks = Timing::from_micros(row.get(2)?, row.get(3)?);
+                Ok(Stats {
+                    avg_pick: picks.average(),
+                    avg_completion: acks.average(),
+                    ..Stats::default()
+                })
+            },
+        )?;
+        let mut stmt =
+            conn.prepare("SELECT status, COUNT(*) FROM jobs WHERE queue = ?1 GROUP BY status")?;
+        let counts = stmt.query_map(params![self.queue], |row| {
+            Ok((
+                status_from_str(&row.get::<_, String>(0)?)?,
+                row.get::<_, i64>(1)?,
+            ))
+        })?;
+        for count in counts {
+            let (status, n) = count?;
+            stats.add(status, n as usize);
+        }
+        let oldest: Option<i64> = conn.query_row(
+            "SELECT MIN(timestamp) FROM jobs WHERE queue = ?1 AND status = ?2",
+            params![self.queue, status_to_str(&JobStatus::PENDING)],
+            |row| row.get(0),
+        )?;
+        stats.oldest_pending = oldest.map(|at| {
+            self.config
+                .clock
+                .now()
+                .duration_since(from_nanos(at))
+                .unwrap_or_default()
+        });
+        Ok(stats)
+    }
+
+    fn now(&self) -> SystemTime {
+        self.config.clock.now()
+    }
+
+    // Pages are read straight off the (queue, id) index, so how deep a page
+    // is doesn't matter, unlike with an OFFSET.
+    fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<Page, QueueError> {
+        let mut sql = "SELECT * FROM jobs WHERE queue = ? AND id > ?".to_string();
+        let mut args = vec![
+            Value::Text(self.queue.clone()),
+            Value::Integer(after.map_or(0, |after| after.min(MAX_JOB_ID) as i64)),
+        ];
+        if let Some(status) = &filter.status {
+            sql.push_str(" AND status = ?");
+            args.push(Value::Text(status_to_str(status).to_string()));
+        }
+        if let Some(kind) = &filter.kind {
+            sql.push_str(" AND kind = ?");
+            args.push(Value::Text(kind.clone()));
+        }
+        if let Some(at) = &filter.created_after {
+            sql.push_str(" AND timestamp >= ?");
+            args.push(Value::Integer(to_nanos(at)));
+        }
+        if let Some(at) = &filter.created_before {
+            sql.push_str(" AND timestamp < ?");
+            args.push(Value::Integer(to_nanos(at)));
+        }
+        if let Some(age) = filter.heartbeat_older_than {
+            let deadline = self
+                .config
+                .clock
+                .now()
+                .checked_sub(age)
+                .unwrap_or(UNIX_EPOCH);
+            sql.push_str(" AND heartbeat < ?");
+            args.push(Value::Integer(to_nanos(&deadline)));
+        }
+        sql.push_str(" ORDER BY id LIMIT ?");
+        args.push(Value::Integer(
+            limit.saturating_add(1).min(i64::MAX as usize) as i64,
+        ));
+
+        let conn = self.conn.lock()?;
+        let mut stmt = conn.prepare(&sql)?;
+        let jobs = stmt
+            .query_map(params_from_iter(args), job_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(Page::from_jobs(jobs, after, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Clock;
+
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("foxtail-{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn enqueue_test() {
+        let q = SqliteQueue::new();
+        q.enqueue(Job::new(1, b"Hello, World!")).unwrap();
+
+        let job = q.get(1).unwrap().expect("job should be stored");
+        assert_eq!(job.get_id(), 1);
+        assert_eq!(job.get_status(), &JobStatus::PENDING);
+        assert_eq!(job.payload, b"Hello, World!");
+    }
+
+    #[test]
+    fn dequeue_test() {
+        let q = SqliteQueue::new();
+        q.enqueue(Job::new(1, b"Hello, World!")).unwrap();
+        assert_eq!(q.len().unwrap(), 1);
+
+        q.dequeue(1).unwrap();
+        assert_eq!(q.len().unwrap(), 0);
+        assert!(matches!(q.dequeue(1), Err(QueueError::NotFound(1))));
+    }
+
+    #[test]
+    fn claim_test() {
+        let q = SqliteQueue::new();
+        q.enqueue(Job::new(1, b"first")).unwrap();
+        q.enqueue(Job::new(2, b"second")).unwrap();
+
+        let job = q.claim().unwrap().unwrap();
+        assert_eq!(job.get_id(), 1);
+        assert_eq!(job.get_status(), &JobStatus::PICKED);
+        assert_eq!(q.get(1).unwrap().unwrap().get_status(), &JobStatus::PICKED);
+
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+        assert!(q.claim().unwrap().is_none());
+    }
+
+    #[test]
+    fn ack_fail_test() {
+        let q = SqliteQueue::new();
+        q.enqueue(Job::new(1, b"ok")).unwrap();
+        q.enqueue(Job::new(2, b"broken")).unwrap();
+        assert!(q.ack(1).is_err());
+
+        q.claim().unwrap();
+        q.claim().unwrap();
+        q.ack(1).unwrap();
+        q.fail(2, "disk full").unwrap();
+
+        assert_eq!(
+            q.get(1).unwrap().unwrap().get_status(),
+            &JobStatus::PROCESSED
+        );
+        let failed = q.get(2).unwrap().unwrap();
+        assert_eq!(failed.get_status(), &JobStatus::FAILED);
+        assert_eq!(failed.errors(), ["disk full"]);
+        assert!(q.ack(2).is_err());
+        assert!(q.ack(3).is_err());
+    }
+
+    #[test]
+    fn reap_test() {
+        let clock = crate::MockClock::default();
+        let q = SqliteQueue::new().with_config(QueueConfig {
+            lease: Duration::from_secs(30),
+            max_expiries: 0,
+            clock: Arc::new(clock.clone()),
+            ..QueueConfig::default()
+        });
+        q.enqueue(Job::new(1, b"slow")).unwrap();
+        q.enqueue(Job::new(2, b"alive")).unwrap();
+        q.claim().unwrap();
+        q.claim().unwrap();
+
+        clock.advance(Duration::from_secs(31));
+        q.heartbeat(2).unwrap();
+        assert_eq!(q.reap().unwrap(), 1);
+
+        let job = q.get(1).unwrap().unwrap();
+        assert_eq!(job.get_status(), &JobStatus::FAILED);
+        assert_eq!(job.expiries(), 1);
+        assert_eq!(q.get(2).unwrap().unwrap().get_status(), &JobStatus::PICKED);
+    }
+
+    #[test]
+    fn mock_clock_test() {
+        let clock = crate::MockClock::new(UNIX_EPOCH + Duration::from_secs(1000));
+        let q = SqliteQueue::new().with_config(QueueConfig {
+            lease: Duration::from_secs(30),
+            max_expiries: 0,
+            clock: Arc::new(clock.clone()),
+            ..QueueConfig::default()
+        });
+        q.enqueue(Job::new_at(1, b"slow", clock.now())).unwrap();
+        q.enqueue(Job::new_at(2, b"later", clock.now()).with_delay(Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 1);
+        assert!(q.claim().unwrap().is_none());
+
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(q.reap().unwrap(), 1);
+        assert_eq!(q.get(1).unwrap().unwrap().get_status(), &JobStatus::FAILED);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+    }
+
+    #[test]
+    fn retry_test() {
+        let q = SqliteQueue::new().with_config(QueueConfig {
+            retry: RetryPolicy::new(3, crate::Backoff::Fixed(Duration::from_millis(50))),
+            ..QueueConfig::default()
+        });
+        let policy = RetryPolicy::new(2, crate::Backoff::Fixed(Duration::ZERO));
+        q.enqueue(Job::new(1, b"flaky").with_retry(policy.clone()))
+            .unwrap();
+
+        q.claim().unwrap();
+        q.fail(1, "timeout").unwrap();
+        let job = q.get(1).unwrap().unwrap();
+        assert_eq!(job.get_status(), &JobStatus::PENDING);
+        assert_eq!(job.retry_policy(), Some(&policy));
+
+        let job = q.claim().unwrap().unwrap();
+        assert_eq!(job.attempts(), 2);
+        q.fail(1, "timeout").unwrap();
+        assert_eq!(q.get(1).unwrap().unwrap().get_status(), &JobStatus::FAILED);
+    }
+
+    #[test]
+    fn scheduled_test() {
+        let q = SqliteQueue::new();
+        q.enqueue(Job::new(1, b"later").with_delay(Duration::from_millis(50)))
+            .unwrap();
+        q.enqueue(Job::new(2, b"now")).unwrap();
+
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+        assert!(q.claim().unwrap().is_none());
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 1);
+    }
+
+    #[test]
+    fn priority_test() {
+        let q = SqliteQueue::new();
+        q.enqueue(Job::new(1, b"low").with_priority(-1)).unwrap();
+        q.enqueue(Job::new(2, b"normal")).unwrap();
+        q.enqueue(Job::new(3, b"urgent").with_priority(10)).unwrap();
+        q.enqueue(Job::new(4, b"normal too")).unwrap();
+
+        let order: Vec<JobId> = std::iter::from_fn(|| q.claim().unwrap())
+            .map(|job| job.get_id())
+            .collect();
+        assert_eq!(order, [3, 2, 4, 1]);
+        assert_eq!(q.get(3).unwrap().unwrap().priority(), 10);
+    }
+
+    #[test]
+    fn claim_wait_test() {
+        let path = temp_db("wait");
+        let q = SqliteQueue::open(&path).unwrap();
+        let producer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(30));
+                // a different connection, like another process would have
+                let q = SqliteQueue::open(&path).unwrap();
+                q.enqueue(Job::new(1, b"from afar")).unwrap();
+            })
+        };
+        let job = q.claim_wait(Duration::MAX).unwrap().unwrap();
+        assert_eq!(job.get_id(), 1);
+        producer.join().unwrap();
+        assert!(q.claim_wait(Duration::from_millis(20)).unwrap().is_none());
+        drop(q);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn typed_payload_test() {
+        let q = SqliteQueue::new();
+        let job = Job::with_payload_codec::<crate::MsgPack, _>(1, &(42u32, "answer")).unwrap();
+        q.enqueue(job).unwrap();
+
+        let job = q.get(1).unwrap().unwrap();
+        assert_eq!(job.codec(), Some("msgpack"));
+        let (n, s): (u32, String) = job.payload_as().unwrap();
+        assert_eq!((n, s.as_str()), (42, "answer"));
+    }
+
+    #[test]
+    fn ids_test() {
+        let q = SqliteQueue::new();
+        assert_eq!(q.enqueue(Job::anonymous(b"a")).unwrap(), 1);
+        assert_eq!(q.enqueue(Job::new(10, b"b")).unwrap(), 10);
+        assert_eq!(q.enqueue(Job::anonymous(b"c")).unwrap(), 11);
+        assert!(matches!(
+            q.enqueue(Job::new(10, b"again")),
+            Err(QueueError::DuplicateId(10))
+        ));
+        assert_eq!(q.len().unwrap(), 3);
+
+        q.dequeue(11).unwrap();
+        assert_eq!(q.enqueue(Job::anonymous(b"d")).unwrap(), 12);
+    }
+
+    #[test]
+    fn store_test() {
+        let path = temp_db("store");
+        {
+            let store = SqliteStore::open(&path).unwrap();
+            let config = QueueConfig {
+                lease: Duration::from_millis(1500),
+                capacity: Some(10),
+                retry: RetryPolicy::new(3, crate::Backoff::Fixed(Duration::from_secs(1))),
+                ids: crate::IdStrategy::TimeOrdered,
+                ..QueueConfig::default()
+            };
+            let emails = store.create_queue("emails", config).unwrap();
+            let webhooks = store
+                .create_queue("webhooks", QueueConfig::default())
+                .unwrap();
+            assert!(matches!(
+                store.create_queue("emails", QueueConfig::default()),
+                Err(QueueError::QueueExists(_))
+            ));
+            emails.enqueue(Job::anonymous(b"hi")).unwrap();
+            webhooks.enqueue(Job::new(1, b"POST")).unwrap();
+            webhooks.enqueue(Job::new(2, b"PUT")).unwrap();
+            // same id, different queue
+            store
+                .create_queue("reports", QueueConfig::default())
+                .unwrap()
+                .enqueue(Job::new(1, b"Q3"))
+                .unwrap();
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.queues().unwrap(), ["emails", "reports", "webhooks"]);
+        let emails = store.queue("emails").unwrap();
+        assert_eq!(emails.config().lease, Duration::from_millis(1500));
+        assert_eq!(emails.config().capacity, Some(10));
+        assert_eq!(emails.config().retry.max_attempts, 3);
+        assert_eq!(emails.config().ids, crate::IdStrategy::TimeOrdered);
+        assert_eq!(emails.len().unwrap(), 1);
+
+        let webhooks = store.queue("webhooks").unwrap();
+        assert_eq!(webhooks.claim().unwrap().unwrap().payload(), b"POST");
+        assert_eq!(
+            store
+                .queue("reports")
+                .unwrap()
+                .get(1)
+                .unwrap()
+                .unwrap()
+                .payload(),
+            b"Q3"
+        );
+
+        store.delete_queue("webhooks").unwrap();
+        assert!(matches!(
+            store.queue("webhooks"),
+            Err(QueueError::UnknownQueue(_))
+        ));
+        assert!(webhooks.enqueue(Job::anonymous(b"late")).is_err());
+        assert_eq!(store.queues().unwrap(), ["emails", "reports"]);
+        drop((store, emails, webhooks));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn query_test() {
+        let q = SqliteQueue::new();
+        for i in 0..25 {
+            let kind = if i % 5 == 0 { "report" } else { "email" };
+            q.enqueue(Job::anonymous(b"x").with_kind(kind)).unwrap();
+        }
+        q.claim().unwrap();
+        q.claim().unwrap();
+
+        let filter = JobFilter::new()
+            .with_status(JobStatus::PENDING)
+            .with_kind("email");
+        let mut ids = Vec::new();
+        let mut after = None;
+        loop {
+            let page = q.query(&filter, after, 7).unwrap();
+            ids.extend(page.jobs.iter().map(Job::get_id));
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        // 1 and 6 are reports, 2 was claimed
+        assert_eq!(ids.len(), 19);
+        assert_eq!(&ids[..3], [3, 4, 5]);
+
+        let stale = JobFilter::new().with_heartbeat_older_than(Duration::from_millis(20));
+        assert!(q.query(&stale, None, 10).unwrap().jobs.is_empty());
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(q.query(&stale, None, 10).unwrap().jobs.len(), 10);
+
+        let created = *q.get(10).unwrap().unwrap().timestamp();
+        let later = JobFilter::new().with_created_after(created);
+        assert_eq!(q.query(&later, None, 100).unwrap().jobs[0].get_id(), 10);
+    }
+
+    #[test]
+    fn stats_test() {
+        let q = SqliteQueue::new();
+        for _ in 0..4 {
+            q.enqueue(Job::anonymous(b"x")).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        q.claim().unwrap();
+        q.claim().unwrap();
+        q.ack(1).unwrap();
+        q.fail(2, "nope").unwrap();
+
+        let stats = q.stats().unwrap();
+        assert_eq!(
+            (stats.pending, stats.picked, stats.processed, stats.failed),
+            (2, 0, 1, 1)
+        );
+        assert!(stats.oldest_pending.unwrap() >= Duration::from_millis(20));
+        assert!(stats.avg_pick.unwrap() >= Duration::from_millis(20));
+        assert!(stats.avg_completion.unwrap() >= stats.avg_pick.unwrap());
+    }
+
+    #[test]
+    fn schema_version_test() {
+        let path = temp_db("schema");
+        drop(SqliteStore::open(&path).unwrap());
+        let conn = Connection::open(&path).unwrap();
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        drop(conn);
+        assert!(SqliteQueue::open(&path).is_ok());
+
+        // a layout this version doesn't know how to read
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        drop(conn);
+        let err = SqliteStore::open(&path).err().unwrap();
+        assert!(err.to_string().contains("schema version"), "{}", err);
+
+        let _ = std::fs::remove_file(&path);
+
+        let path = temp_db("schema-unversioned");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch("CREATE TABLE jobs (seq INTEGER PRIMARY KEY, id INTEGER NOT NULL)")
+            .unwrap();
+        drop(conn);
+        let err = SqliteStore::open(&path).err().unwrap();
+        assert!(err.to_string().contains("schema version 0"), "{}", err);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let path = temp_db("reopen");
+        let j = Job::new_at(7, b"persist me", SystemTime::now()).with_kind("email");
+        let timestamp = *j.timestamp();
+        {
+            let q = SqliteQueue::open(&path).unwrap();
+            q.enqueue(j).unwrap();
+            q.enqueue(Job::anonymous(b"gone")).unwrap();
+            q.dequeue(8).unwrap();
+        }
+
+        let q = SqliteQueue::open(&path).unwrap();
+        assert_eq!(q.len().unwrap(), 1);
+        let job = q.get(7).unwrap().unwrap();
+        assert_eq!(job.payload, b"persist me");
+        assert_eq!(job.timestamp(), &timestamp);
+        assert_eq!(job.kind(), "email");
+        assert_eq!(q.enqueue(Job::anonymous(b"new")).unwrap(), 9);
+        drop(q);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use crate::JobStatus;
+
+// A snapshot of a queue. Counts are of the jobs in the queue right now,
+// `oldest_pending` is the age of the oldest PENDING job. The averages are
+// over every pick and every ack since the queue was created, measured from
+// the job timestamp, and are None until there has been one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub pending: usize,
+    pub picked: usize,
+    pub processed: usize,
+    pub failed: usize,
+    pub oldest_pending: Option<Duration>,
+    pub avg_pick: Option<Duration>,
+    pub avg_completion: Option<Duration>,
+}
+
+impl Stats {
+    pub fn count(&self, status: JobStatus) -> usize {
+        match status {
+            JobStatus::PENDING => self.pending,
+            JobStatus::PICKED => self.picked,
+            JobStatus::PROCESSED => self.processed,
+            JobStatus::FAILED => self.failed,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.pending + self.picked + self.processed + self.failed
+    }
+
+    pub(crate) fn add(&mut self, status: JobStatus, n: usize) {
+        match status {
+            JobStatus::PENDING => self.pending += n,
+            JobStatus::PICKED => self.picked += n,
+            JobStatus::PROCESSED => self.processed += n,
+            JobStatus::FAILED => self.failed += n,
+        }
+    }
+}
+
+// A running average of durations.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Timing {
+    count: u64,
+    total: Duration,
+}
+
+impl Timing {
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn from_micros(count: u64, micros: u64) -> Self {
+        Timing {
+            count,
+            total: Duration::from_micros(micros),
+        }
+    }
+
+    pub(crate) fn record(&mut self, d: Duration) {
+        self.count += 1;
+        self.total = self.total.saturating_add(d);
+    }
+
+    pub(crate) fn average(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let nanos = self.total.as_nanos() / self.count as u128;
+        Some(Duration::from_nanos(nanos.min(u64::MAX as u128) as u64))
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::{InMemQueue, JobQueue, QueueConfig, QueueError};
+
+// Many named queues over a single backend. Each queue has its own jobs, ids
+// and config; the handles returned are regular JobQueues.
+pub trait QueueStore {
+    type Queue: JobQueue;
+
+    // Fails with QueueExists if there's already a queue with this name.
+    fn create_queue(&self, name: &str, config: QueueConfig) -> Result<Self::Queue, QueueError>;
+    // Fails with UnknownQueue if there's no queue with this name.
+    fn queue(&self, name: &str) -> Result<Self::Queue, QueueError>;
+    // The names of every queue, sorted.
+    fn queues(&self) -> Result<Vec<String>, QueueError>;
+    // Removes the queue along with all of its jobs.
+    fn delete_queue(&self, name: &str) -> Result<(), QueueError>;
+}
+
+// InMemQueues by name. Handles to a deleted queue keep working, but on jobs
+// the store no longer knows about.
+#[derive(Default)]
+pub struct InMemStore {
+    queues: Mutex<BTreeMap<String, InMemQueue>>,
+}
+
+impl InMemStore {
+    pub fn new() -> Self {
+        InMemStore::default()
+    }
+}
+
+impl QueueStore for InMemStore {
+    type Queue = InMemQueue;
+
+    fn create_queue(&self, name: &str, config: QueueConfig) -> Result<InMemQueue, QueueError> {
+        let mut queues = self.queues.lock()?;
+        if queues.contains_key(name) {
+            return Err(QueueError::QueueExists(name.to_string()));
+        }
+        let queue = InMemQueue::new().with_config(config);
+        queues.insert(name.to_string(), queue.clone());
+        Ok(queue)
+    }
+
+    fn queue(&self, name: &str) -> Result<InMemQueue, QueueError> {
+        self.queues
+            .lock()?
+            .get(name)
+            .cloned()
+            .ok_or_else(|| QueueError::UnknownQueue(name.to_string()))
+    }
+
+    fn queues(&self) -> Result<Vec<String>, QueueError> {
+        Ok(self.queues.lock()?.keys().cloned().collect())
+    }
+
+    fn delete_queue(&self, name: &str) -> Result<(), QueueError> {
+        self.queues
+            .lock()?
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| QueueError::UnknownQueue(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Job;
+    use std::time::Duration;
+
+    #[test]
+    fn in_mem_store_test() {
+        let store = InMemStore::new();
+        let emails = store
+            .create_queue(
+                "emails",
+                QueueConfig {
+                    lease: Duration::from_secs(5),
+                    ..QueueConfig::default()
+                },
+            )
+            .unwrap();
+        store
+            .create_queue("reports", QueueConfig::default())
+            .unwrap();
+        assert!(matches!(
+            store.create_queue("emails", QueueConfig::default()),
+            Err(QueueError::QueueExists(_))
+        ));
+        assert_eq!(store.queues().unwrap(), ["emails", "reports"]);
+        let lease = store.queue("emails").unwrap().config().lease;
+        assert_eq!(lease, Duration::from_secs(5));
+
+        // every queue has its own jobs and ids
+        emails.enqueue(Job::anonymous(b"hi")).unwrap();
+        let reports = store.queue("reports").unwrap();
+        assert_eq!(reports.enqueue(Job::anonymous(b"q3")).unwrap(), 1);
+        assert_eq!(store.queue("emails").unwrap().len().unwrap(), 1);
+        assert_eq!(reports.len().unwrap(), 1);
+
+        store.delete_queue("emails").unwrap();
+        assert!(matches!(
+            store.queue("emails"),
+            Err(QueueError::UnknownQueue(_))
+        ));
+        assert!(store.delete_queue("emails").is_err());
+        assert_eq!(store.queues().unwrap(), ["reports"]);
+    }
+}
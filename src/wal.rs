@@ -0,0 +1,629 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::memory::State;
+use crate::wire::{decode_job, encode_job, get_u64, get_u8, invalid, take, to_nanos};
+use crate::{Job, JobFilter, JobId, JobQueue, JobStatus, Page, QueueConfig, QueueError, Stats};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"FOXSNAP1";
+
+const PUT: u8 = 1;
+const REMOVE: u8 = 2;
+
+// When appended records are forced to disk. Records are written to the file
+// as they happen either way, so a process crash doesn't lose any; this is
+// about surviving the machine going down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    // fsync after every record.
+    Always,
+    // fsync at most once per interval, on the first record after it elapsed.
+    Every(Duration),
+    // Leave it to the operating system.
+    Never,
+}
+
+// The same data structures as InMemQueue, made durable by a write-ahead
+// log. Every change to a job appends the job as it will be (or its removal)
+// to `log` before it is made, so a change that couldn't be logged doesn't
+// happen at all. Opening the queue replays the log on top of the last
+// snapshot. Once the log holds `compact_after` records and more than twice
+// as many as there are jobs, the jobs are written to a fresh snapshot and
+// the log starts over.
+//
+// Since records hold whole jobs, replaying one twice is harmless: a crash
+// between writing a snapshot and truncating the log only means some records
+// are applied again on the next start. A record torn by a crash is
+// detected by its checksum, and the log is cut off before it.
+//
+// Only one process at a time can have the queue open: it holds a lock on
+// `dir/lock` until the last handle is dropped.
+//
+// The pick and ack timings in `stats` only cover what happened since the
+// queue was opened.
+pub struct WalQueue {
+    inner: Arc<Mutex<Inner>>,
+    available: Arc<Condvar>,
+    config: QueueConfig,
+}
+
+struct Inner {
+    state: State,
+    dir: PathBuf,
+    log: File,
+    records: usize,
+    durability: Durability,
+    last_sync: Instant,
+    compact_after: usize,
+    // held, never read
+    _lock: File,
+    // `dir` goes away with the queue
+    temporary: bool,
+}
+
+impl WalQueue {
+    // Opens the queue stored in `dir`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, QueueError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join("lock"))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let msg = format!("{} is in use by another process", dir.display());
+                return Err(QueueError::Backend(msg.into()));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        let mut state = State::default();
+        if let Some(snapshot) = read_if_exists(&dir.join("snapshot"))? {
+            load_snapshot(&snapshot, &mut state)?;
+        }
+        let log_path = dir.join("log");
+        let (records, valid) = match read_if_exists(&log_path)? {
+            Some(log) => replay(&log, &mut state)?,
+            None => (0, 0),
+        };
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        // drop whatever a crash left half written
+        log.set_len(valid as u64)?;
+        Ok(WalQueue {
+            inner: Arc::new(Mutex::new(Inner {
+                state,
+                dir,
+                log,
+                records,
+                durability: Durability::Always,
+                last_sync: Instant::now(),
+                compact_after: 10_000,
+                _lock: lock,
+                temporary: false,
+            })),
+            available: Arc::new(Condvar::new()),
+            config: QueueConfig::default(),
+        })
+    }
+
+    pub fn with_config(mut self, config: QueueConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_durability(self, durability: Durability) -> Self {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.durability = durability;
+        }
+        self
+    }
+
+    pub fn with_compact_after(self, records: usize) -> Self {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.compact_after = records;
+        }
+        self
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    // Writes a snapshot and empties the log right away.
+    pub fn compact(&self) -> Result<(), QueueError> {
+        Ok(self.inner.lock()?.compact()?)
+    }
+
+    fn update<F>(&self, id_job: JobId, f: F) -> Result<(), QueueError>
+    where
+        F: FnOnce(&mut Job, SystemTime) -> Result<(), QueueError>,
+    {
+        let now = self.config.clock.now();
+        self.inner.lock()?.change(id_job, now, |job| f(job, now))
+    }
+
+    fn notify(&self) {
+        self.available.notify_all();
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+impl Inner {
+    // Applies `f` to a job: it runs on a copy, which is logged and only then
+    // replaces the job.
+    fn change<F, T>(&mut self, id_job: JobId, now: SystemTime, f: F) -> Result<T, QueueError>
+    where
+        F: FnOnce(&mut Job) -> Result<T, QueueError>,
+    {
+        let seq = self
+            .state
+            .find(id_job)
+            .ok_or(QueueError::NotFound(id_job))?;
+        let mut job = self
+            .state
+            .job(seq)
+            .cloned()
+            .ok_or(QueueError::NotFound(id_job))?;
+        let res = f(&mut job)?;
+        let mut body = vec![PUT];
+        encode_job(&job, &mut body);
+        self.append(&body)?;
+        self.state.modify(seq, now, |old| *old = job);
+        Ok(res)
+    }
+
+    // A due compaction happens before the record is written: right after,
+    // the change it records isn't in `state` yet and the snapshot would
+    // miss it.
+    fn append(&mut self, body: &[u8]) -> Result<(), QueueError> {
+        if self.records >= self.compact_after && self.records > 2 * self.state.len() {
+            self.compact()?;
+        }
+        let len = self.log.metadata()?.len();
+        let mut record = Vec::with_capacity(body.len() + 8);
+        record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(body).to_le_bytes());
+        record.extend_from_slice(body);
+        if let Err(e) = self.write(&record) {
+            // the change is reported as failed, so it mustn't come back on
+            // replay, and later records mustn't land behind a torn one
+            let _ = self.log.set_len(len);
+            return Err(e.into());
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    fn claim(&mut self, now: SystemTime) -> Result<Option<Job>, QueueError> {
+        let Some(seq) = self.state.next_due(now) else {
+            return Ok(None);
+        };
+        let Some(id_job) = self.state.job(seq).map(|job| job.id) else {
+            return Ok(None);
+        };
+        self.change(id_job, now, |job| {
+            job.pick(now)?;
+            Ok(job.clone())
+        })
+        .map(Some)
+    }
+
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        self.log.write_all(record)?;
+        match self.durability {
+            Durability::Always => self.log.sync_data()?,
+            Durability::Every(interval) if self.last_sync.elapsed() >= interval => {
+                self.log.sync_data()?;
+                self.last_sync = Instant::now();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // The snapshot is written aside and renamed over the old one, so there's
+    // always a complete one on disk.
+    fn compact(&mut self) -> io::Result<()> {
+        let mut buf = SNAPSHOT_MAGIC.to_vec();
+        buf.extend_from_slice(&self.state.last_id().to_le_bytes());
+        buf.extend_from_slice(&(self.state.len() as u64).to_le_bytes());
+        for job in self.state.jobs() {
+            encode_job(job, &mut buf);
+        }
+        let tmp = self.dir.join("snapshot.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join("snapshot"))?;
+        // make the rename itself durable before dropping the log, where the
+        // platform lets directories be synced
+        if let Ok(dir) = File::open(&self.dir) {
+            let _ = dir.sync_all();
+        }
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.records = 0;
+        Ok(())
+    }
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)?;
+            Ok(Some(buf))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn load_snapshot(mut buf: &[u8], state: &mut State) -> io::Result<()> {
+    if take(&mut buf, 8)? != SNAPSHOT_MAGIC {
+        return Err(invalid("not a foxtail snapshot"));
+    }
+    state.raise_last_id(get_u64(&mut buf)?);
+    for _ in 0..get_u64(&mut buf)? {
+        state.put(decode_job(&mut buf)?);
+    }
+    Ok(())
+}
+
+// Applies the records of `log` to `state`, returning how many there were
+// and where the last complete one ends.
+fn replay(log: &[u8], state: &mut State) -> io::Result<(usize, usize)> {
+    let mut records = 0;
+    let mut pos = 0;
+    while log.len() - pos >= 8 {
+        let len = u32::from_le_bytes(log[pos..pos + 4].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(log[pos + 4..pos + 8].try_into().unwrap());
+        let Some(body) = log.get(pos + 8..pos + 8 + len) else {
+            break;
+        };
+        if checksum(body) != sum {
+            break;
+        }
+        let mut buf = body;
+        match get_u8(&mut buf)? {
+            PUT => {
+                let job = decode_job(&mut buf)?;
+                state.raise_last_id(job.id);
+                state.put(job);
+            }
+            REMOVE => {
+                if let Some(seq) = state.find(get_u64(&mut buf)?) {
+                    state.remove(seq);
+                }
+            }
+            other => return Err(invalid(&format!("unknown record type {}", other))),
+        }
+        records += 1;
+        pos += 8 + len;
+    }
+    Ok((records, pos))
+}
+
+// FNV-1a, good enough to tell a torn write from a complete record.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+impl JobQueue for WalQueue {
+    // A queue created through the trait logs to a fresh directory under the
+    // system temp dir; use `WalQueue::open` to pick where.
+    // A queue in a fresh temporary directory, removed along with the last
+    // handle.
+    fn new() -> Self {
+        let nanos = to_nanos(&SystemTime::now());
+        let dir =
+            std::env::temp_dir().join(format!("foxtail-wal-{}-{}", std::process::id(), nanos));
+        let q = WalQueue::open(dir).expect("Failed to create write-ahead log");
+        if let Ok(mut inner) = q.inner.lock() {
+            inner.temporary = true;
+        }
+        q
+    }
+
+    fn enqueue(&self, j: Job) -> Result<JobId, QueueError> {
+        let mut inner = self.inner.lock()?;
+        let job = inner.state.admit(j, &self.config)?;
+        let id = job.id;
+        let mut body = vec![PUT];
+        encode_job(&job, &mut body);
+        inner.append(&body)?;
+        inner.state.insert(job);
+        drop(inner);
+        self.notify();
+        Ok(id)
+    }
+
+    fn get(&self, id_job: JobId) -> Result<Option<Job>, QueueError> {
+        Ok(self.inner.lock()?.state.lookup(id_job).cloned())
+    }
+
+    fn dequeue(&self, id_job: JobId) -> Result<(), QueueError> {
+        let mut inner = self.inner.lock()?;
+        let seq = inner
+            .state
+            .find(id_job)
+            .ok_or(QueueError::NotFound(id_job))?;
+        let mut body = vec![REMOVE];
+        body.extend_from_slice(&id_job.to_le_bytes());
+        inner.append(&body)?;
+        inner.state.remove(seq);
+        Ok(())
+    }
+
+    fn dequeue_if(&self, id_job: JobId, status: JobStatus) -> Result<Job, QueueError> {
+        let mut inner = self.inner.lock()?;
+        let seq = inner.state.find_in(id_job, status)?;
+        let mut body = vec![REMOVE];
+        body.extend_from_slice(&id_job.to_le_bytes());
+        inner.append(&body)?;
+        inner.state.remove(seq).ok_or(QueueError::NotFound(id_job))
+    }
+
+    fn claim(&self) -> Result<Option<Job>, QueueError> {
+        self.inner.lock()?.claim(self.config.clock.now())
+    }
+
+    fn claim_wait(&self, timeout: Duration) -> Result<Option<Job>, QueueError> {
+        // too far out to be an Instant is as good as forever
+        let deadline = Instant::now().checked_add(timeout);
+        let mut inner = self.inner.lock()?;
+        loop {
+            let now = self.config.clock.now();
+            if let Some(job) = inner.claim(now)? {
+                return Ok(Some(job));
+            }
+            let left = deadline.map_or(Duration::MAX, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            if left.is_zero() {
+                return Ok(None);
+            }
+            let wait = match inner.state.next_scheduled() {
+                Some(at) => at.duration_since(now).unwrap_or_default().min(left),
+                None => left,
+            };
+            inner = self.available.wait_timeout(inner, wait)?.0;
+        }
+    }
+
+    fn ack(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, _| job.ack())
+    }
+
+    fn fail(&self, id_job: JobId, reason: &str) -> Result<(), QueueError> {
+        self.update(id_job, |job, now| job.fail(reason, &self.config.retry, now))?;
+        self.notify();
+        Ok(())
+    }
+
+    fn retry(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, _| job.revive())?;
+        self.notify();
+        Ok(())
+    }
+
+    fn heartbeat(&self, id_job: JobId) -> Result<(), QueueError> {
+        self.update(id_job, |job, now| job.touch(now))
+    }
+
+    fn reap(&self) -> Result<usize, QueueError> {
+        let now = self.config.clock.now();
+        let mut inner = self.inner.lock()?;
+        let expired = inner.state.expired(now, &self.config);
+        let mut reaped = 0;
+        let res = expired.into_iter().try_for_each(|id_job| {
+            inner.change(id_job, now, |job| job.expire(self.config.max_expiries))?;
+            reaped += 1;
+            Ok(())
+        });
+        drop(inner);
+        // the ones reaped before a failure are back in the queue too
+        if reaped > 0 {
+            self.notify();
+        }
+        res.map(|()| reaped)
+    }
+
+    fn len(&self) -> Result<usize, QueueError> {
+        Ok(self.inner.lock()?.state.len())
+    }
+
+    fn list(&self) -> Result<Vec<Job>, QueueError> {
+        Ok(self.inner.lock()?.state.jobs().cloned().collect())
+    }
+
+    fn now(&self) -> SystemTime {
+        self.config.clock.now()
+    }
+
+    fn query(
+        &self,
+        filter: &JobFilter,
+        after: Option<JobId>,
+        limit: usize,
+    ) -> Result<Page, QueueError> {
+        let inner = self.inner.lock()?;
+        Ok(inner
+            .state
+            .query(filter, after, limit, self.config.clock.now()))
+    }
+
+    fn stats(&self) -> Result<Stats, QueueError> {
+        Ok(self.inner.lock()?.state.stats(self.config.clock.now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("foxtail-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn replay_test() {
+        let dir = temp_dir("wal-replay");
+        {
+            let q = WalQueue::open(&dir).unwrap();
+            for _ in 0..4 {
+                q.enqueue(Job::anonymous(b"x")).unwrap();
+            }
+            q.claim().unwrap();
+            q.claim().unwrap();
+            q.ack(1).unwrap();
+            q.fail(2, "nope").unwrap();
+            q.dequeue(4).unwrap();
+        }
+
+        let q = WalQueue::open(&dir).unwrap();
+        // not while it's open, even from the same process
+        let err = WalQueue::open(&dir).err().unwrap();
+        assert!(err.to_string().contains("in use"), "{}", err);
+        assert_eq!(q.len().unwrap(), 3);
+        assert_eq!(
+            q.get(1).unwrap().unwrap().get_status(),
+            &JobStatus::PROCESSED
+        );
+        assert_eq!(q.get(2).unwrap().unwrap().errors(), ["nope"]);
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 3);
+        // 4 is gone but its id isn't handed out again
+        assert_eq!(q.enqueue(Job::anonymous(b"y")).unwrap(), 5);
+        drop(q);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn temporary_test() {
+        let q = WalQueue::new();
+        q.enqueue(Job::anonymous(b"x")).unwrap();
+        let dir = q.inner.lock().unwrap().dir.clone();
+        assert!(dir.join("log").exists());
+        drop(q);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn torn_write_test() {
+        let dir = temp_dir("wal-torn");
+        {
+            let q = WalQueue::open(&dir)
+                .unwrap()
+                .with_durability(Durability::Never);
+            q.enqueue(Job::new(1, b"safe")).unwrap();
+        }
+        let log = dir.join("log");
+        let len = fs::metadata(&log).unwrap().len();
+        // a second record that only made it halfway
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&[200, 0, 0, 0, 1, 2, 3, 4, PUT, 9]).unwrap();
+        drop(file);
+
+        let q = WalQueue::open(&dir).unwrap();
+        assert_eq!(q.len().unwrap(), 1);
+        assert_eq!(fs::metadata(&log).unwrap().len(), len);
+        q.enqueue(Job::new(2, b"after")).unwrap();
+        drop(q);
+        assert_eq!(WalQueue::open(&dir).unwrap().len().unwrap(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compaction_test() {
+        let dir = temp_dir("wal-compact");
+        {
+            let q = WalQueue::open(&dir)
+                .unwrap()
+                .with_durability(Durability::Every(Duration::from_millis(10)))
+                .with_compact_after(20);
+            for _ in 0..50 {
+                let id = q.enqueue(Job::anonymous(b"churn")).unwrap();
+                q.claim().unwrap();
+                q.ack(id).unwrap();
+                q.dequeue(id).unwrap();
+            }
+            q.enqueue(Job::anonymous(b"keep")).unwrap();
+            let log = fs::metadata(dir.join("log")).unwrap().len();
+            assert!(
+                log < 2000,
+                "log should have been compacted, is {} bytes",
+                log
+            );
+            assert!(dir.join("snapshot").exists());
+        }
+
+        let q = WalQueue::open(&dir).unwrap();
+        assert_eq!(q.len().unwrap(), 1);
+        assert_eq!(q.get(51).unwrap().unwrap().payload(), b"keep");
+        assert_eq!(q.enqueue(Job::anonymous(b"next")).unwrap(), 52);
+
+        q.compact().unwrap();
+        assert_eq!(fs::metadata(dir.join("log")).unwrap().len(), 0);
+        drop(q);
+        assert_eq!(WalQueue::open(&dir).unwrap().len().unwrap(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_append_test() {
+        let dir = temp_dir("wal-failed-append");
+        let q = WalQueue::open(&dir).unwrap();
+        q.enqueue(Job::new(1, b"picked")).unwrap();
+        q.enqueue(Job::new(2, b"pending")).unwrap();
+        q.claim().unwrap();
+        let len = fs::metadata(dir.join("log")).unwrap().len();
+
+        // a log that can't be written to
+        let writable = {
+            let mut inner = q.inner.lock().unwrap();
+            let read_only = File::open(dir.join("log")).unwrap();
+            std::mem::replace(&mut inner.log, read_only)
+        };
+        assert!(q.enqueue(Job::new(3, b"lost")).is_err());
+        assert!(q.claim().is_err());
+        assert!(q.ack(1).is_err());
+        assert!(q.fail(1, "nope").is_err());
+        assert!(q.dequeue(2).is_err());
+
+        // none of which happened
+        assert_eq!(q.len().unwrap(), 2);
+        assert!(q.get(3).unwrap().is_none());
+        assert_eq!(q.get(1).unwrap().unwrap().get_status(), &JobStatus::PICKED);
+        assert_eq!(q.get(2).unwrap().unwrap().get_status(), &JobStatus::PENDING);
+        assert_eq!(q.stats().unwrap().count(JobStatus::PROCESSED), 0);
+        assert_eq!(fs::metadata(dir.join("log")).unwrap().len(), len);
+
+        q.inner.lock().unwrap().log = writable;
+        assert_eq!(q.claim().unwrap().unwrap().get_id(), 2);
+        drop(q);
+        let q = WalQueue::open(&dir).unwrap();
+        assert_eq!(q.len().unwrap(), 2);
+        assert_eq!(q.get(2).unwrap().unwrap().get_status(), &JobStatus::PICKED);
+        drop(q);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,179 @@
+// The binary encoding of jobs shared by the write-ahead log and the network
+// protocol.
+use std::io::{self, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Job, JobStatus, RetryPolicy};
+
+pub(crate) fn invalid(what: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, what.to_string())
+}
+
+pub(crate) fn to_nanos(t: &SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+pub(crate) fn from_nanos(n: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(n)
+}
+
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+pub(crate) fn put_opt_str(buf: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            buf.push(1);
+            put_bytes(buf, s.as_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+// Fixed-width little-endian integers, strings and byte strings prefixed by
+// their u32 length, options by a 0/1 byte.
+pub(crate) fn encode_job(job: &Job, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&job.id.to_le_bytes());
+    buf.push(job.status as u8);
+    put_bytes(buf, &job.payload);
+    buf.extend_from_slice(&to_nanos(&job.timestamp).to_le_bytes());
+    buf.extend_from_slice(&to_nanos(&job.heartbeat).to_le_bytes());
+    buf.extend_from_slice(&(job.errors.len() as u32).to_le_bytes());
+    for e in &job.errors {
+        put_bytes(buf, e.as_bytes());
+    }
+    buf.extend_from_slice(&job.expiries.to_le_bytes());
+    buf.extend_from_slice(&job.attempts.to_le_bytes());
+    put_opt_str(
+        buf,
+        job.retry.as_ref().map(RetryPolicy::to_string).as_deref(),
+    );
+    match &job.run_at {
+        Some(at) => {
+            buf.push(1);
+            buf.extend_from_slice(&to_nanos(at).to_le_bytes());
+        }
+        None => buf.push(0),
+    }
+    buf.extend_from_slice(&job.priority.to_le_bytes());
+    put_bytes(buf, job.kind.as_bytes());
+    put_opt_str(buf, job.codec.as_deref());
+}
+
+pub(crate) fn get_status(buf: &mut &[u8]) -> io::Result<JobStatus> {
+    match get_u8(buf)? {
+        0 => Ok(JobStatus::PENDING),
+        1 => Ok(JobStatus::PICKED),
+        2 => Ok(JobStatus::PROCESSED),
+        3 => Ok(JobStatus::FAILED),
+        other => Err(invalid(&format!("unknown status {}", other))),
+    }
+}
+
+pub(crate) fn take<'a>(buf: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < n {
+        return Err(invalid("truncated message"));
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(head)
+}
+
+pub(crate) fn get_u8(buf: &mut &[u8]) -> io::Result<u8> {
+    Ok(take(buf, 1)?[0])
+}
+
+pub(crate) fn get_u32(buf: &mut &[u8]) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(take(buf, 4)?.try_into().unwrap()))
+}
+
+pub(crate) fn get_u64(buf: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(take(buf, 8)?.try_into().unwrap()))
+}
+
+pub(crate) fn get_bytes(buf: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = get_u32(buf)? as usize;
+    Ok(take(buf, len)?.to_vec())
+}
+
+pub(crate) fn get_string(buf: &mut &[u8]) -> io::Result<String> {
+    String::from_utf8(get_bytes(buf)?).map_err(|_| invalid("invalid utf-8"))
+}
+
+pub(crate) fn get_opt_string(buf: &mut &[u8]) -> io::Result<Option<String>> {
+    match get_u8(buf)? {
+        0 => Ok(None),
+        _ => get_string(buf).map(Some),
+    }
+}
+
+pub(crate) fn decode_job(buf: &mut &[u8]) -> io::Result<Job> {
+    let id = get_u64(buf)?;
+    let status = get_status(buf)?;
+    let payload = get_bytes(buf)?;
+    let timestamp = from_nanos(get_u64(buf)?);
+    let heartbeat = from_nanos(get_u64(buf)?);
+    let errors = (0..get_u32(buf)?)
+        .map(|_| get_string(buf))
+        .collect::<io::Result<Vec<_>>>()?;
+    let expiries = get_u32(buf)?;
+    let attempts = get_u32(buf)?;
+    let retry = get_opt_string(buf)?
+        .map(|s| s.parse::<RetryPolicy>().map_err(|e| invalid(&e)))
+        .transpose()?;
+    let run_at = match get_u8(buf)? {
+        0 => None,
+        _ => Some(from_nanos(get_u64(buf)?)),
+    };
+    let priority = get_u32(buf)? as i32;
+    let kind = get_string(buf)?;
+    let codec = get_opt_string(buf)?;
+    Ok(Job {
+        id,
+        status,
+        payload,
+        timestamp,
+        heartbeat,
+        errors,
+        expiries,
+        attempts,
+        retry,
+        run_at,
+        priority,
+        kind,
+        codec,
+        stamped: true,
+        delay: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Backoff;
+
+    #[test]
+    fn encoding_test() {
+        let mut job = Job::new_at(u64::MAX, b"\x00binary\xff", SystemTime::now())
+            .with_kind("email")
+            .with_priority(-3)
+            .with_retry(RetryPolicy::new(5, Backoff::Fixed(Duration::from_secs(2))))
+            .with_run_at(SystemTime::now() + Duration::from_secs(60));
+        job.codec = Some("json".to_string());
+        job.errors = vec!["first".to_string(), "ünïcode".to_string()];
+        job.status = JobStatus::FAILED;
+        job.attempts = 2;
+
+        let mut buf = Vec::new();
+        encode_job(&job, &mut buf);
+        let mut slice = &buf[..];
+        let decoded = decode_job(&mut slice).unwrap();
+        assert!(slice.is_empty());
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", job));
+        assert!(decode_job(&mut &buf[..buf.len() - 1]).is_err());
+    }
+}
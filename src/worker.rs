@@ -0,0 +1,223 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{Job, JobQueue};
+
+// Runs a claimed job. An Err fails the job with that reason, which lets its
+// retry policy decide what comes next; Ok acks it.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, job: &Job) -> Result<(), String>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Job) -> Result<(), String> + Send + Sync + 'static,
+{
+    fn handle(&self, job: &Job) -> Result<(), String> {
+        self(job)
+    }
+}
+
+// `heartbeat` should be comfortably shorter than the lease of the queue the
+// pool works on, `poll` is how long an idle worker waits for a job before
+// checking whether it was asked to stop.
+#[derive(Clone, Debug)]
+pub struct WorkerConfig {
+    pub concurrency: usize,
+    pub heartbeat: Duration,
+    pub poll: Duration,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            concurrency: 4,
+            heartbeat: Duration::from_secs(10),
+            poll: Duration::from_millis(250),
+        }
+    }
+}
+
+// A set of threads claiming jobs from a queue and running them through a
+// handler. Each job runs on its own thread while the worker that claimed it
+// keeps its lease alive, then acks or fails it. Shutting down lets the jobs
+// in flight finish.
+pub struct WorkerPool {
+    stop: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn spawn<Q, H>(queue: Arc<Q>, config: WorkerConfig, handler: H) -> Self
+    where
+        Q: JobQueue + Send + Sync + 'static,
+        H: Handler,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler = Arc::new(handler);
+        let workers = (0..config.concurrency.max(1))
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let handler = Arc::clone(&handler);
+                let stop = Arc::clone(&stop);
+                let config = config.clone();
+                thread::spawn(move || work(&*queue, &*handler, &config, &stop))
+            })
+            .collect();
+        WorkerPool { stop, workers }
+    }
+
+    pub fn shutdown(mut self) {
+        self.stop_workers();
+    }
+
+    fn stop_workers(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.stop_workers();
+    }
+}
+
+fn work<Q: JobQueue, H: Handler>(queue: &Q, handler: &H, config: &WorkerConfig, stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        match queue.claim_wait(config.poll) {
+            Ok(Some(job)) => run(queue, handler, config, &job),
+            Ok(None) => {}
+            // the backend is having trouble, don't spin on it
+            Err(_) => thread::sleep(config.poll),
+        }
+    }
+}
+
+fn run<Q: JobQueue, H: Handler>(queue: &Q, handler: &H, config: &WorkerConfig, job: &Job) {
+    let id = job.get_id();
+    let outcome = thread::scope(|s| {
+        let (done, finished) = mpsc::channel();
+        let running = s.spawn(move || {
+            let _ = done.send(handler.handle(job));
+        });
+        loop {
+            match finished.recv_timeout(config.heartbeat) {
+                Ok(outcome) => return outcome,
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = queue.heartbeat(id);
+                }
+                // the handler panicked before reporting back
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = running.join();
+                    return Err("handler panicked".to_string());
+                }
+            }
+        }
+    });
+    // Errors here mean the lease was lost and the job reaped in the
+    // meantime, it's someone else's now.
+    let _ = match outcome {
+        Ok(()) => queue.ack(id),
+        Err(reason) => queue.fail(id, &reason),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemQueue, JobStatus, QueueConfig};
+    use std::sync::atomic::AtomicUsize;
+
+    fn config() -> WorkerConfig {
+        WorkerConfig {
+            concurrency: 3,
+            heartbeat: Duration::from_millis(10),
+            poll: Duration::from_millis(10),
+        }
+    }
+
+    fn wait_until(f: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if f() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn pool_test() {
+        let q = Arc::new(InMemQueue::new());
+        for id in 1..=20 {
+            q.enqueue(Job::new(id, if id % 5 == 0 { b"bad" } else { b"ok!" }))
+                .unwrap();
+        }
+
+        let processed = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&processed);
+        let pool = WorkerPool::spawn(Arc::clone(&q), config(), move |job: &Job| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            match job.payload() {
+                b"bad" => Err("bad payload".to_string()),
+                b"ok!" => Ok(()),
+                _ => panic!("unexpected payload"),
+            }
+        });
+        wait_until(|| {
+            q.list().unwrap().iter().all(|job| {
+                job.get_status() != &JobStatus::PENDING && job.get_status() != &JobStatus::PICKED
+            })
+        });
+        pool.shutdown();
+
+        assert_eq!(processed.load(Ordering::SeqCst), 20);
+        for job in q.list().unwrap() {
+            if job.get_id() % 5 == 0 {
+                assert_eq!(job.get_status(), &JobStatus::FAILED);
+                assert_eq!(job.last_error(), Some("bad payload"));
+            } else {
+                assert_eq!(job.get_status(), &JobStatus::PROCESSED);
+            }
+        }
+    }
+
+    #[test]
+    fn heartbeat_and_panic_test() {
+        let q = Arc::new(InMemQueue::new().with_config(QueueConfig {
+            lease: Duration::from_millis(40),
+            ..QueueConfig::default()
+        }));
+        q.enqueue(Job::new(1, b"slow")).unwrap();
+        q.enqueue(Job::new(2, b"panic")).unwrap();
+
+        let pool = WorkerPool::spawn(Arc::clone(&q), config(), |job: &Job| {
+            if job.payload() == b"panic" {
+                panic!("boom");
+            }
+            thread::sleep(Duration::from_millis(150));
+            Ok(())
+        });
+        // the slow job outlives its lease several times over, but the worker
+        // keeps it alive
+        for _ in 0..10 {
+            thread::sleep(Duration::from_millis(15));
+            q.reap().unwrap();
+        }
+        wait_until(|| q.get(1).unwrap().unwrap().get_status() == &JobStatus::PROCESSED);
+        wait_until(|| q.get(2).unwrap().unwrap().get_status() == &JobStatus::FAILED);
+        pool.shutdown();
+
+        assert_eq!(q.get(1).unwrap().unwrap().expiries(), 0);
+        assert_eq!(
+            q.get(2).unwrap().unwrap().last_error(),
+            Some("handler panicked")
+        );
+    }
+}